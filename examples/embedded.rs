@@ -0,0 +1,19 @@
+//! Traces a shader embedded in the binary, with no filesystem access.
+//!
+//! Run with `cargo run --example embedded --features include_dir`.
+
+extern crate glslwatch;
+extern crate include_dir;
+
+use glslwatch::{EmbeddedResolver, GLSLTree};
+use include_dir::{include_dir, Dir};
+
+static SHADERS: Dir = include_dir!("$CARGO_MANIFEST_DIR/src/test_glsl");
+
+fn main() {
+    let tree = GLSLTree::builder("simple.vert")
+        .resolver(EmbeddedResolver::new(&SHADERS))
+        .build()
+        .expect("embedded shader tree");
+    println!("{}", tree);
+}
@@ -0,0 +1,673 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use conditional::{self, Conditional};
+use preprocess::{AnnotatedGLSL, Profile};
+use resolver::{FsResolver, Resolver};
+use {Error, GLSLTree, Result, Warning};
+
+/// Behavior supplied by the user, shared by a builder and the trees it builds.
+pub(crate) struct Hook<F: ?Sized>(pub Arc<F>);
+
+impl<F: ?Sized> Clone for Hook<F> {
+    fn clone(&self) -> Self {
+        Hook(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+/// Resolves an include path, given the including file, to a file path.
+pub(crate) type ResolveInclude = dyn Fn(&str, &str) -> Option<String> + Send + Sync;
+
+/// Decides whether to follow an include, given the include path and the including file.
+pub(crate) type IncludeFilter = dyn Fn(&str, &str) -> bool + Send + Sync;
+
+/// Transforms the source of a file, given its path, before it is parsed.
+pub(crate) type SourceTransform = dyn Fn(&str, &str) -> String + Send + Sync;
+
+/// Post-processes the rendered output.
+pub(crate) type RenderTransform = dyn Fn(String) -> String + Send + Sync;
+
+/// Reads the source at a path in place of the resolver, or declines with `None`.
+pub(crate) type ReadOverride = dyn Fn(&str) -> Option<io::Result<String>> + Send + Sync;
+
+/// The line ending of rendered output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NewlineStyle {
+    Lf,
+    CrLf,
+}
+
+impl NewlineStyle {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            NewlineStyle::Lf => "\n",
+            NewlineStyle::CrLf => "\r\n",
+        }
+    }
+}
+
+/// How an include is resolved when more than one include directory holds its path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmbiguityPolicy {
+    /// The file in the first include directory which holds it wins.
+    FirstFound,
+    /// The most recently modified file wins, e.g. so edits to an override layer take
+    /// precedence. Files whose resolver reports no modification time are the oldest.
+    Newest,
+}
+
+/// How a tree is rendered when its files declare different versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionPolicy {
+    /// Included files must declare the root's version, or tracing fails with
+    /// `Error::VersionMismatch`.
+    Strict,
+    /// The tree is rendered as the root's version, whatever its included files declare.
+    RootWins,
+    /// The tree is rendered as the highest version declared by any of its files.
+    Highest,
+}
+
+/// Settings that shape how a tree is traced and rendered. They are kept with the tree so a
+/// refresh traces the same way the original construction did.
+#[derive(Debug, Clone)]
+pub(crate) struct Options {
+    pub default_version: usize,
+    pub hoist_extensions: bool,
+    pub strict_version: bool,
+    pub include_suffixes: Vec<String>,
+    pub normalize_backslashes: bool,
+    pub max_includes_per_file: usize,
+    pub max_file_size: u64,
+    pub defines: Vec<(String, String)>,
+    pub prune_conditionals: bool,
+    pub validate_include_dirs: bool,
+    pub version_handling: bool,
+    pub import_alias: bool,
+    pub include_overrides: HashMap<String, String>,
+    pub resolve_include: Option<Hook<ResolveInclude>>,
+    pub include_filter: Option<Hook<IncludeFilter>>,
+    pub strip_license_headers: bool,
+    pub allow_back_edges: bool,
+    pub resolver: Hook<dyn Resolver>,
+    pub sandbox_root: Option<PathBuf>,
+    pub required_within: Option<PathBuf>,
+    pub default_profile: Option<Profile>,
+    pub require_version: bool,
+    /// Include paths to trace in place of others, set with `GLSLTree::remap_include`.
+    pub include_remaps: HashMap<String, String>,
+    /// Sources to trace instead of loading them, keyed like a tree's sources, while
+    /// rerooting a tree.
+    pub trace_cache: Option<Arc<HashMap<String, AnnotatedGLSL>>>,
+    pub reject_padded_include_paths: bool,
+    pub inherit_default_profile: bool,
+    pub case_insensitive_includes: bool,
+    pub newline_style: NewlineStyle,
+    pub source_transform: Option<Hook<SourceTransform>>,
+    pub version_mismatch_allowed: Vec<String>,
+    pub error_on_empty_output: bool,
+    pub version_macro: Option<String>,
+    pub library_mode: bool,
+    pub ambiguity_policy: AmbiguityPolicy,
+    pub version_policy: VersionPolicy,
+    /// Text to render in place of marker lines, keyed by the trimmed marker.
+    pub injections: HashMap<String, String>,
+    pub read_override: Option<Hook<ReadOverride>>,
+    pub render_transform: Option<Hook<RenderTransform>>,
+    pub module_roots: Vec<PathBuf>,
+    pub manifest: HashMap<String, PathBuf>,
+    pub warn_mixed_indentation: bool,
+    pub preserve_raw: bool,
+    /// Where errors in includes are collected, when tracing continues past them.
+    pub error_sink: Option<Arc<Mutex<Vec<Error>>>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            default_version: 110,
+            hoist_extensions: false,
+            strict_version: false,
+            include_suffixes: Vec::new(),
+            normalize_backslashes: false,
+            max_includes_per_file: 256,
+            max_file_size: 16 * 1024 * 1024,
+            defines: Vec::new(),
+            prune_conditionals: false,
+            validate_include_dirs: false,
+            version_handling: true,
+            import_alias: false,
+            include_overrides: HashMap::new(),
+            resolve_include: None,
+            include_filter: None,
+            strip_license_headers: false,
+            allow_back_edges: false,
+            resolver: Hook(Arc::new(FsResolver)),
+            sandbox_root: None,
+            required_within: None,
+            default_profile: None,
+            require_version: false,
+            include_remaps: HashMap::new(),
+            trace_cache: None,
+            reject_padded_include_paths: false,
+            error_sink: None,
+            inherit_default_profile: false,
+            case_insensitive_includes: false,
+            newline_style: NewlineStyle::Lf,
+            source_transform: None,
+            version_mismatch_allowed: Vec::new(),
+            error_on_empty_output: false,
+            version_macro: None,
+            library_mode: false,
+            ambiguity_policy: AmbiguityPolicy::FirstFound,
+            version_policy: VersionPolicy::Strict,
+            injections: HashMap::new(),
+            read_override: None,
+            render_transform: None,
+            module_roots: Vec::new(),
+            manifest: HashMap::new(),
+            warn_mixed_indentation: false,
+            preserve_raw: false,
+        }
+    }
+}
+
+impl Options {
+    /// Returns whether the include filter, if any, lets the given file's include be followed.
+    pub fn follows_include(&self, include: &str, includer: &str) -> bool {
+        match self.include_filter {
+            Some(ref filter) => (filter.0)(include, includer),
+            None => true,
+        }
+    }
+
+    /// Returns whether a version line is synthesized at the start of the rendered output.
+    pub fn renders_version_line(&self) -> bool {
+        self.version_handling && !self.library_mode
+    }
+
+    /// Returns the ranges of lines in dead branches of the given conditionals, evaluated for
+    /// the given version. No branch is dead unless conditional pruning is enabled.
+    pub fn dead_ranges(
+        &self,
+        conditionals: &HashMap<usize, Conditional>,
+        version: usize,
+    ) -> Vec<Range<usize>> {
+        if self.prune_conditionals {
+            conditional::dead_ranges(conditionals, &self.conditional_defines(version))
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Returns the macros conditionals are evaluated with: the defines, and the version
+    /// macro, if set, as the given version.
+    fn conditional_defines(&self, version: usize) -> Cow<'_, [(String, String)]> {
+        match self.version_macro {
+            Some(ref token) => {
+                let mut defines = self.defines.clone();
+                defines.push((token.clone(), version.to_string()));
+                Cow::Owned(defines)
+            }
+            None => Cow::Borrowed(&self.defines),
+        }
+    }
+}
+
+pub(crate) fn owned_defines<S: AsRef<str>, S2: AsRef<str>>(
+    defines: &[(S, S2)],
+) -> Vec<(String, String)> {
+    defines
+        .iter()
+        .map(|(name, value)| (String::from(name.as_ref()), String::from(value.as_ref())))
+        .collect()
+}
+
+/// Configures the construction of a `GLSLTree`. Obtain one from `GLSLTree::builder`.
+///
+/// ```
+/// let src_tree = GLSLTree::builder("shaders/frag.glsl")
+///     .include_dirs(&["shaders/include"])
+///     .default_version(330)
+///     .build()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct GLSLTreeBuilder {
+    root: PathBuf,
+    include_dirs: Vec<PathBuf>,
+    options: Options,
+}
+
+impl GLSLTreeBuilder {
+    pub(crate) fn new<P: AsRef<Path>>(root: P) -> Self {
+        GLSLTreeBuilder {
+            root: root.as_ref().to_path_buf(),
+            include_dirs: Vec::new(),
+            options: Options::default(),
+        }
+    }
+
+    /// Sets the directories searched for included files, in priority order.
+    pub fn include_dirs<P: AsRef<Path>>(mut self, include_dirs: &[P]) -> Self {
+        self.include_dirs = include_dirs
+            .iter()
+            .map(|dir| dir.as_ref().to_path_buf())
+            .collect();
+        self
+    }
+
+    /// Sets the roots of modules which includes are written relative to, as in a monorepo.
+    /// An include is resolved against each module root holding it, ahead of the include
+    /// directories, and where several do, the longest root wins, so nested modules take
+    /// precedence over the modules containing them.
+    pub fn module_roots<P: AsRef<Path>>(mut self, module_roots: &[P]) -> Self {
+        self.options.module_roots = module_roots
+            .iter()
+            .map(|root| root.as_ref().to_path_buf())
+            .collect();
+        self
+    }
+
+    /// Sets the version assumed when the root has no version pragma. Defaults to 110.
+    pub fn default_version(mut self, default_version: usize) -> Self {
+        self.options.default_version = default_version;
+        self
+    }
+
+    /// Moves every unique `#extension` directive in the tree to just after the `#version`
+    /// line, which is the only place strict compilers accept them. Directives inside
+    /// conditional blocks, such as `#ifdef GL_OES_standard_derivatives`, are left in place
+    /// with their guard.
+    ///
+    /// When disabled (the default), `#extension` directives are emitted where they appear.
+    /// A header which is deduplicated at a later include site still contributes its
+    /// extensions at its first include site, so no extension is ever dropped.
+    pub fn hoist_extensions(mut self, hoist_extensions: bool) -> Self {
+        self.options.hoist_extensions = hoist_extensions;
+        self
+    }
+
+    /// Requires the root to have a version pragma, failing with `Error::MissingVersion`
+    /// otherwise, instead of assuming the default version. Off by default, and ignored when
+    /// version handling is disabled.
+    pub fn require_version(mut self, require_version: bool) -> Self {
+        self.options.require_version = require_version;
+        self
+    }
+
+    /// Replaces the given token, e.g. `__GLSL_VERSION__`, with the version of the rendered
+    /// output wherever it appears in the sources, so headers can adapt to the root's version
+    /// with e.g. `#if __GLSL_VERSION__ >= 330`. With `prune_conditionals`, conditionals
+    /// choosing which files are included see the token as the version too. Unset by default.
+    pub fn version_macro(mut self, version_macro: Option<String>) -> Self {
+        self.options.version_macro = version_macro;
+        self
+    }
+
+    /// Sets how a tree whose files declare different versions is rendered, which is
+    /// `VersionPolicy::Strict` by default. Files declaring a version other than the rendered
+    /// one are reported by `GLSLTree::warnings`.
+    pub fn version_policy(mut self, version_policy: VersionPolicy) -> Self {
+        self.options.version_policy = version_policy;
+        self
+    }
+
+    /// Exempts the given files from the check that included files declare the root's
+    /// version, for headers known to be compatible with it. Files are given by their include
+    /// path as written or by their resolved path. Other files are still checked.
+    pub fn allow_version_mismatch_for<S: AsRef<str>>(mut self, paths: &[S]) -> Self {
+        self.options.version_mismatch_allowed =
+            paths.iter().map(|path| String::from(path.as_ref())).collect();
+        self
+    }
+
+    /// Requires the root's version pragma to come before anything but whitespace and
+    /// comments, as GLSL does, failing with `Error::MisplacedVersion` otherwise. Off by
+    /// default, in which case a version pragma anywhere in the root is accepted.
+    pub fn strict_version(mut self, strict_version: bool) -> Self {
+        self.options.strict_version = strict_version;
+        self
+    }
+
+    /// Sets the profile assumed when the root has no version pragma, which is none by
+    /// default.
+    pub fn default_profile(mut self, default_profile: Profile) -> Self {
+        self.options.default_profile = Some(default_profile);
+        self
+    }
+
+    /// Makes a root version pragma which names no profile, like `#version 330`, take the
+    /// default profile. Off by default, in which case the default profile only applies to
+    /// roots without a version pragma, and a pragma is rendered as written.
+    pub fn inherit_default_profile(mut self, inherit_default_profile: bool) -> Self {
+        self.options.inherit_default_profile = inherit_default_profile;
+        self
+    }
+
+    /// Strips everything from the first occurrence of any of the given markers to the end of
+    /// each include path before resolving it, for asset pipelines which annotate include
+    /// paths, e.g. `#include "common.glsl?v=2"` with the marker `"?"`. No paths are
+    /// stripped by default.
+    pub fn strip_include_suffixes<S: AsRef<str>>(mut self, markers: &[S]) -> Self {
+        self.options.include_suffixes = markers
+            .iter()
+            .map(|marker| String::from(marker.as_ref()))
+            .collect();
+        self
+    }
+
+    /// Replaces backslashes in include paths with forward slashes before resolving them, for
+    /// shaders written on Windows, e.g. `#include "sub\common.glsl"`. Off by default, in
+    /// which case such an include which can't be found fails with `Error::BackslashInclude`
+    /// on platforms where `\` isn't a path separator.
+    pub fn normalize_backslashes(mut self, normalize_backslashes: bool) -> Self {
+        self.options.normalize_backslashes = normalize_backslashes;
+        self
+    }
+
+    /// Rejects include paths with whitespace just inside their delimiters, as in
+    /// `#include " common.glsl "`, failing with `Error::MalformedInclude`. Off by default,
+    /// in which case such paths are trimmed.
+    pub fn reject_padded_include_paths(mut self, reject_padded_include_paths: bool) -> Self {
+        self.options.reject_padded_include_paths = reject_padded_include_paths;
+        self
+    }
+
+    /// Sets how many include directives a single file may contain before tracing fails with
+    /// `Error::TooManyIncludes`, to catch runaway generated code. Defaults to 256.
+    pub fn max_includes_per_file(mut self, max_includes_per_file: usize) -> Self {
+        self.options.max_includes_per_file = max_includes_per_file;
+        self
+    }
+
+    /// Sets how many bytes a single file may hold before tracing fails with
+    /// `Error::FileTooLarge`, so an include of a huge generated or binary file is caught
+    /// before it is read. Defaults to 16 MiB. Files whose resolver can't tell their size
+    /// are not checked.
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.options.max_file_size = max_file_size;
+        self
+    }
+
+    /// Sets `#define`s to inject after the version line (and any hoisted extensions), as
+    /// `(name, value)` pairs. An empty value defines the name without a value. They can be
+    /// replaced after construction with `GLSLTree::set_defines`. With `prune_conditionals`,
+    /// they also decide which includes are traced.
+    pub fn defines<S: AsRef<str>, S2: AsRef<str>>(mut self, defines: &[(S, S2)]) -> Self {
+        self.options.defines = owned_defines(defines);
+        self
+    }
+
+    /// Evaluates conditional directives over the injected defines to decide which includes
+    /// are traced: includes in dead branches of `#if`, `#ifdef`, `#ifndef`, `#elif` and
+    /// `#else` are neither loaded nor rendered. All other lines are passed through for the
+    /// compiler to evaluate. Off by default, so the includes of every branch are traced.
+    ///
+    /// Conditions of `#if` and `#elif` may use:
+    ///
+    /// * decimal integer literals,
+    /// * identifiers, which evaluate to the integer value of the injected define of that
+    ///   name, or 0 if it isn't defined or its value isn't an integer,
+    /// * `defined(NAME)` and `defined NAME`, which evaluate to 1 if the name is an injected
+    ///   define and 0 otherwise,
+    /// * parentheses, unary `!`, `-` and `+`, `&&`, `||`, `==`, `!=`, `<`, `<=`, `>` and
+    ///   `>=`, with C precedence.
+    ///
+    /// Only injected defines, and the version macro if set, are considered; `#define`s in
+    /// the sources and macros the compiler predefines, such as `__VERSION__`, are not, and
+    /// count as undefined. A condition outside this subset can't be evaluated, so every
+    /// branch of its group is traced.
+    pub fn prune_conditionals(mut self, prune_conditionals: bool) -> Self {
+        self.options.prune_conditionals = prune_conditionals;
+        self
+    }
+
+    /// Checks up front that every include directory exists and is a directory, failing with
+    /// `Error::InvalidIncludeDir` otherwise. Off by default, so directories which don't
+    /// exist yet are simply skipped when searching.
+    pub fn validate_include_dirs(mut self, validate_include_dirs: bool) -> Self {
+        self.options.validate_include_dirs = validate_include_dirs;
+        self
+    }
+
+    /// Keeps the source of each file exactly as read, for `GLSLTree::render_raw`, at the cost
+    /// of holding every source twice. Off by default, and has no effect with a source
+    /// transform.
+    pub fn preserve_raw(mut self, preserve_raw: bool) -> Self {
+        self.options.preserve_raw = preserve_raw;
+        self
+    }
+
+    /// Enables processing of `#version` pragmas, which is on by default. When disabled,
+    /// `#version` lines are passed through like any other line, no version line is
+    /// synthesized and versions are never compared, leaving only include flattening. This
+    /// suits languages which borrow `#include` but not `#version`, such as WGSL or HLSL.
+    pub fn version_handling(mut self, version_handling: bool) -> Self {
+        self.options.version_handling = version_handling;
+        self
+    }
+
+    /// Builds the tree as a library of code meant only to be included elsewhere: no version
+    /// line is synthesized and included files may declare any version, leaving just the
+    /// flattened body. Version pragmas of the sources are still stripped. Unlike
+    /// `GLSLTree::render_without_version`, which only changes the output, this also skips
+    /// the version mismatch checks while tracing. Off by default.
+    pub fn library_mode(mut self, library_mode: bool) -> Self {
+        self.options.library_mode = library_mode;
+        self
+    }
+
+    /// Treats `#import "path"` exactly like `#include "path"`, as some engines spell it.
+    /// Off by default, in which case `#import` lines are passed through untouched.
+    pub fn import_alias(mut self, import_alias: bool) -> Self {
+        self.options.import_alias = import_alias;
+        self
+    }
+
+    /// Treats include paths which differ only in case as the same file, so it is loaded and
+    /// emitted once however it is spelled, on every platform. The first spelling traced is
+    /// the one loaded, so on case-sensitive filesystems it must match the file's name. Off
+    /// by default.
+    pub fn case_insensitive_includes(mut self, case_insensitive_includes: bool) -> Self {
+        self.options.case_insensitive_includes = case_insensitive_includes;
+        self
+    }
+
+    /// Sets a manifest mapping logical include names, such as `color-utils`, to the files
+    /// they stand for, as some shader libraries ship. The manifest is the first step of
+    /// resolving an include; names it doesn't hold are resolved as usual.
+    pub fn manifest(mut self, manifest: HashMap<String, PathBuf>) -> Self {
+        self.options.manifest = manifest;
+        self
+    }
+
+    /// Pins includes to exact files, mapping an include path as written in a directive to
+    /// the file to load for it. Mapped includes skip the include directories entirely,
+    /// which settles ambiguous includes without reordering the directories.
+    pub fn include_overrides(mut self, include_overrides: HashMap<String, String>) -> Self {
+        self.options.include_overrides = include_overrides;
+        self
+    }
+
+    /// Makes `GLSLTree::warnings` report files whose indentation mixes tabs and spaces, for
+    /// linters which care. This never affects rendering. Off by default.
+    pub fn warn_mixed_indentation(mut self, warn_mixed_indentation: bool) -> Self {
+        self.options.warn_mixed_indentation = warn_mixed_indentation;
+        self
+    }
+
+    /// Splices text into the rendered output at marker lines, mapping a marker, such as
+    /// `// @INJECT uniforms`, to the text rendered in place of each line holding just that
+    /// marker, e.g. generated uniform blocks. Markers which appear nowhere are ignored.
+    pub fn with_injections(mut self, injections: HashMap<String, String>) -> Self {
+        self.options.injections = injections
+            .into_iter()
+            .map(|(marker, text)| (String::from(marker.trim()), text))
+            .collect();
+        self
+    }
+
+    /// Sets a function consulted to resolve each include, with the include path as written
+    /// in the directive and the including file as keyed in the tree. When it returns a file
+    /// path, that file is loaded for the include; when it returns `None`, the include is
+    /// resolved as usual. Include overrides take precedence over this function.
+    pub fn resolve_include<F>(mut self, resolve_include: F) -> Self
+    where
+        F: Fn(&str, &str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.options.resolve_include = Some(Hook(Arc::new(resolve_include)));
+        self
+    }
+
+    /// Sets a function deciding whether to follow each include, given the include path as
+    /// written in the directive and the including file as keyed in the tree, e.g. to skip
+    /// debug-only headers in release builds. An include it rejects is left out as if it
+    /// weren't there: its file isn't read and nothing is rendered in its place.
+    pub fn include_filter<F>(mut self, include_filter: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        self.options.include_filter = Some(Hook(Arc::new(include_filter)));
+        self
+    }
+
+    /// Sets a function consulted before the resolver to read each path a file is looked for
+    /// at. When it returns a result, that is the result of reading the path; when it returns
+    /// `None`, the resolver reads the path as usual. Sources it returns never expire, as no
+    /// file backs them.
+    pub fn read_override<F>(mut self, read_override: F) -> Self
+    where
+        F: Fn(&str) -> Option<io::Result<String>> + Send + Sync + 'static,
+    {
+        self.options.read_override = Some(Hook(Arc::new(read_override)));
+        self
+    }
+
+    /// Strips the leading comment block, such as a license header, from every included
+    /// file, keeping only the root's, so a header shared by many files appears once in the
+    /// rendered output. The block is the run of comment lines at the very start of a file,
+    /// up to the first line which isn't entirely comment. Off by default.
+    pub fn strip_license_headers(mut self, strip_license_headers: bool) -> Self {
+        self.options.strip_license_headers = strip_license_headers;
+        self
+    }
+
+    /// Treats an include of a file which is already being included further up the chain,
+    /// such as the root, as already included, so it renders as nothing instead of failing
+    /// with `Error::Cycle`. Off by default.
+    ///
+    /// This silences every cycle, including accidental ones. The re-included file is only
+    /// emitted at its outer include site, so anything the including file expects it to
+    /// declare may not have been declared yet at that point of the rendered output.
+    pub fn allow_back_edges(mut self, allow_back_edges: bool) -> Self {
+        self.options.allow_back_edges = allow_back_edges;
+        self
+    }
+
+    /// Sets a function to run the source of every file loaded through before it is parsed,
+    /// given the path it was loaded from and its source as read. The returned source is
+    /// what is traced and rendered, so it may add or remove directives.
+    pub fn source_transform<F>(mut self, source_transform: F) -> Self
+    where
+        F: Fn(&str, &str) -> String + Send + Sync + 'static,
+    {
+        self.options.source_transform = Some(Hook(Arc::new(source_transform)));
+        self
+    }
+
+    /// Sets a function to run the rendered output through once, after the version line and
+    /// defines are added, e.g. to add a footer. Its result is what `GLSLTree::render`
+    /// returns. Mappings of output lines to their sources, such as `GLSLTree::origin`, are
+    /// of the output before the transform, so they shift if it adds or removes lines before
+    /// the end.
+    pub fn render_transform<F>(mut self, render_transform: F) -> Self
+    where
+        F: Fn(String) -> String + Send + Sync + 'static,
+    {
+        self.options.render_transform = Some(Hook(Arc::new(render_transform)));
+        self
+    }
+
+    /// Sets where sources are read from, which is the filesystem by default.
+    pub fn resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+        self.options.resolver = Hook(Arc::new(resolver));
+        self
+    }
+
+    /// Confines includes to the given directory: any include which resolves to a file
+    /// outside it, e.g. through `..` or a symlink, fails with `Error::IncludeEscapesSandbox`.
+    /// Both the directory and each resolved include are canonicalized for the check, so they
+    /// must exist on the filesystem. Use this when tracing untrusted shaders. Unset by
+    /// default.
+    pub fn sandbox_root<P: AsRef<Path>>(mut self, sandbox_root: P) -> Self {
+        self.options.sandbox_root = Some(sandbox_root.as_ref().to_path_buf());
+        self
+    }
+
+    /// Requires every file of the tree, the root included, to resolve within the given
+    /// directory, failing with `Error::OutsideRequiredDir` otherwise. This catches
+    /// dependencies on files outside a checked-in source tree, for reproducible builds.
+    /// Paths are canonicalized for the check, as with `sandbox_root`. Unset by default.
+    pub fn require_within<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.options.required_within = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets how includes found in more than one include directory are resolved. By default
+    /// the first include directory holding the file wins. Every candidate is read to find
+    /// the newest, so `AmbiguityPolicy::Newest` costs a read per include directory.
+    pub fn ambiguity_policy(mut self, ambiguity_policy: AmbiguityPolicy) -> Self {
+        self.options.ambiguity_policy = ambiguity_policy;
+        self
+    }
+
+    /// Sets the line ending of the rendered output, which is `NewlineStyle::Lf` by default.
+    /// Sources are read the same way whatever their line endings.
+    pub fn newline_style(mut self, newline_style: NewlineStyle) -> Self {
+        self.options.newline_style = newline_style;
+        self
+    }
+
+    /// Fails with `Error::EmptyOutput` if the flattened sources render nothing but
+    /// whitespace, which compiles to an empty shader. Off by default.
+    pub fn error_on_empty_output(mut self, error_on_empty_output: bool) -> Self {
+        self.options.error_on_empty_output = error_on_empty_output;
+        self
+    }
+
+    /// Traces and renders the configured tree.
+    pub fn build(self) -> Result<GLSLTree> {
+        GLSLTree::build(&self.root, &self.include_dirs, None, self.options)
+    }
+
+    /// Traces and renders the configured tree, and returns it with its warnings, as
+    /// `GLSLTree::warnings` would return them.
+    pub fn build_with_warnings(self) -> Result<(GLSLTree, Vec<Warning>)> {
+        let tree = self.build()?;
+        let warnings = tree.warnings();
+        Ok((tree, warnings))
+    }
+
+    /// Traces the configured tree to find every error in it, rather than stopping at the
+    /// first. An include which fails to trace, e.g. because it can't be found, is skipped
+    /// with its error collected, and tracing goes on. Fails outright only on errors in the
+    /// root itself, such as failing to load it.
+    pub fn validate(mut self) -> Result<Vec<Error>> {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        self.options.error_sink = Some(errors.clone());
+        self.build()?;
+        let mut errors = errors.lock().unwrap();
+        Ok(errors.drain(..).collect())
+    }
+}
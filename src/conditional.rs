@@ -0,0 +1,353 @@
+//! Evaluation of conditional directives, so that only includes in live branches are traced.
+//! The supported subset is documented on `GLSLTreeBuilder::prune_conditionals`.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// A conditional directive, with its condition or macro name as written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conditional {
+    If(String),
+    Ifdef(String),
+    Ifndef(String),
+    Elif(String),
+    Else,
+    Endif,
+}
+
+/// An open `#if` group.
+struct Group {
+    /// Whether the group is inside a live branch of its enclosing group.
+    parent_live: bool,
+    /// Whether the current branch is live.
+    live: bool,
+    /// Whether an earlier branch was live.
+    taken: bool,
+    /// Whether a condition in the group could not be evaluated.
+    unknown: bool,
+    /// The first line of the current branch.
+    start: usize,
+}
+
+/// Returns the ranges of lines in dead branches, given the conditional directives of a file
+/// keyed by line index.
+pub fn dead_ranges(
+    conditionals: &HashMap<usize, Conditional>,
+    defines: &[(String, String)],
+) -> Vec<Range<usize>> {
+    let mut lines: Vec<&usize> = conditionals.keys().collect();
+    lines.sort();
+
+    let mut dead = Vec::new();
+    let mut groups: Vec<Group> = Vec::new();
+    let close = |group: &Group, end: usize, dead: &mut Vec<Range<usize>>| {
+        if group.parent_live && !group.live {
+            dead.push(group.start..end);
+        }
+    };
+    for &i in lines {
+        match conditionals[&i] {
+            Conditional::If(_) | Conditional::Ifdef(_) | Conditional::Ifndef(_) => {
+                let condition = match conditionals[&i] {
+                    Conditional::If(ref expr) => evaluate(expr, defines),
+                    Conditional::Ifdef(ref name) => Some(is_defined(name, defines)),
+                    Conditional::Ifndef(ref name) => Some(!is_defined(name, defines)),
+                    _ => unreachable!(),
+                };
+                let parent_live = groups.last().map(|g| g.live).unwrap_or(true);
+                groups.push(Group {
+                    parent_live,
+                    live: parent_live && condition.unwrap_or(true),
+                    taken: condition == Some(true),
+                    unknown: condition.is_none(),
+                    start: i + 1,
+                });
+            }
+            Conditional::Elif(ref expr) => {
+                if let Some(group) = groups.last_mut() {
+                    close(group, i, &mut dead);
+                    if group.unknown {
+                        group.live = group.parent_live;
+                    } else if group.taken {
+                        group.live = false;
+                    } else {
+                        let condition = evaluate(expr, defines);
+                        group.live = group.parent_live && condition.unwrap_or(true);
+                        group.taken = condition == Some(true);
+                        group.unknown = condition.is_none();
+                    }
+                    group.start = i + 1;
+                }
+            }
+            Conditional::Else => {
+                if let Some(group) = groups.last_mut() {
+                    close(group, i, &mut dead);
+                    group.live = group.parent_live && (group.unknown || !group.taken);
+                    group.taken = true;
+                    group.start = i + 1;
+                }
+            }
+            Conditional::Endif => {
+                if let Some(group) = groups.pop() {
+                    close(&group, i, &mut dead);
+                }
+            }
+        }
+    }
+    // Unterminated groups run to the end of the file.
+    for group in groups {
+        close(&group, usize::MAX, &mut dead);
+    }
+    dead
+}
+
+/// Returns the ranges of lines inside conditional groups, live or dead, given the
+/// conditional directives of a file keyed by line index.
+pub fn guarded_ranges(conditionals: &HashMap<usize, Conditional>) -> Vec<Range<usize>> {
+    let mut lines: Vec<&usize> = conditionals.keys().collect();
+    lines.sort();
+
+    let mut guarded = Vec::new();
+    let mut starts = Vec::new();
+    for &i in lines {
+        match conditionals[&i] {
+            Conditional::If(_) | Conditional::Ifdef(_) | Conditional::Ifndef(_) => {
+                starts.push(i + 1)
+            }
+            Conditional::Endif => {
+                if let Some(start) = starts.pop() {
+                    guarded.push(start..i);
+                }
+            }
+            Conditional::Elif(_) | Conditional::Else => (),
+        }
+    }
+    // Unterminated groups run to the end of the file.
+    guarded.extend(starts.into_iter().map(|start| start..usize::MAX));
+    guarded
+}
+
+fn is_defined(name: &str, defines: &[(String, String)]) -> bool {
+    defines.iter().any(|(define, _)| define == name)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 13] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", "+", "-",
+];
+
+fn tokenize(expr: &str) -> Option<Vec<Token>> {
+    let expr = match expr.find("//") {
+        Some(i) => &expr[..i],
+        None => expr,
+    };
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while !rest.is_empty() {
+        let first = rest.chars().next().unwrap();
+        let len = if first.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            tokens.push(Token::Int(rest[..len].parse().ok()?));
+            len
+        } else if first.is_alphabetic() || first == '_' {
+            let len = rest
+                .find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(String::from(&rest[..len])));
+            len
+        } else {
+            let op = OPERATORS.iter().find(|op| rest.starts_with(*op))?;
+            tokens.push(Token::Op(op));
+            op.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Some(tokens)
+}
+
+/// Evaluates a condition, returning `None` if it is outside the supported subset.
+fn evaluate(expr: &str, defines: &[(String, String)]) -> Option<bool> {
+    let tokens = tokenize(expr)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        defines,
+    };
+    let value = parser.or()?;
+    if parser.tokens.is_empty() {
+        Some(value != 0)
+    } else {
+        None
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    defines: &'a [(String, String)],
+}
+
+impl<'a> Parser<'a> {
+    fn eat(&mut self, op: &str) -> bool {
+        match self.tokens.first() {
+            Some(Token::Op(o)) if *o == op => {
+                self.tokens = &self.tokens[1..];
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Option<i64> {
+        let mut value = self.and()?;
+        while self.eat("||") {
+            let rhs = self.and()?;
+            value = (value != 0 || rhs != 0) as i64;
+        }
+        Some(value)
+    }
+
+    fn and(&mut self) -> Option<i64> {
+        let mut value = self.equality()?;
+        while self.eat("&&") {
+            let rhs = self.equality()?;
+            value = (value != 0 && rhs != 0) as i64;
+        }
+        Some(value)
+    }
+
+    fn equality(&mut self) -> Option<i64> {
+        let mut value = self.relational()?;
+        loop {
+            if self.eat("==") {
+                value = (value == self.relational()?) as i64;
+            } else if self.eat("!=") {
+                value = (value != self.relational()?) as i64;
+            } else {
+                return Some(value);
+            }
+        }
+    }
+
+    fn relational(&mut self) -> Option<i64> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat("<=") {
+                value = (value <= self.unary()?) as i64;
+            } else if self.eat(">=") {
+                value = (value >= self.unary()?) as i64;
+            } else if self.eat("<") {
+                value = (value < self.unary()?) as i64;
+            } else if self.eat(">") {
+                value = (value > self.unary()?) as i64;
+            } else {
+                return Some(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Option<i64> {
+        if self.eat("!") {
+            Some((self.unary()? == 0) as i64)
+        } else if self.eat("-") {
+            self.unary()?.checked_neg()
+        } else if self.eat("+") {
+            self.unary()
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Option<i64> {
+        if self.eat("(") {
+            let value = self.or()?;
+            return if self.eat(")") { Some(value) } else { None };
+        }
+        let (token, rest) = self.tokens.split_first()?;
+        self.tokens = rest;
+        match token {
+            Token::Int(value) => Some(*value),
+            Token::Ident(name) if name == "defined" => {
+                let parenthesized = self.eat("(");
+                let name = match self.tokens.split_first() {
+                    Some((Token::Ident(name), rest)) => {
+                        self.tokens = rest;
+                        name
+                    }
+                    _ => return None,
+                };
+                if parenthesized && !self.eat(")") {
+                    return None;
+                }
+                Some(is_defined(name, self.defines) as i64)
+            }
+            Token::Ident(name) => Some(
+                self.defines
+                    .iter()
+                    .find(|(define, _)| define == name)
+                    .and_then(|(_, value)| value.trim().parse().ok())
+                    .unwrap_or(0),
+            ),
+            Token::Op(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn defines() -> Vec<(String, String)> {
+        vec![
+            (String::from("FANCY"), String::new()),
+            (String::from("QUALITY"), String::from("2")),
+        ]
+    }
+
+    #[test]
+    fn evaluates_supported_subset() {
+        let defines = defines();
+        assert_eq!(evaluate("defined(FANCY)", &defines), Some(true));
+        assert_eq!(evaluate("defined PLAIN", &defines), Some(false));
+        assert_eq!(evaluate("!defined(PLAIN) && QUALITY >= 2", &defines), Some(true));
+        assert_eq!(evaluate("QUALITY > 2 || defined(PLAIN)", &defines), Some(false));
+        assert_eq!(evaluate("(QUALITY == 2) && UNDEFINED == 0", &defines), Some(true));
+        assert_eq!(evaluate("QUALITY != 2 // comment", &defines), Some(false));
+        assert_eq!(evaluate("QUALITY * 2", &defines), None);
+        assert_eq!(evaluate("(QUALITY", &defines), None);
+        let defines = vec![(String::from("MIN"), i64::MIN.to_string())];
+        assert_eq!(evaluate("-MIN < 0", &defines), None);
+        assert_eq!(evaluate("-(-MIN) == 0", &defines), None);
+    }
+
+    #[test]
+    fn finds_dead_branches() {
+        let conditionals = hashmap! {
+            1 => Conditional::Ifdef(String::from("PLAIN")),
+            3 => Conditional::Elif(String::from("QUALITY > 1")),
+            4 => Conditional::If(String::from("QUALITY * 2")),
+            6 => Conditional::Else,
+            8 => Conditional::Endif,
+            10 => Conditional::Else,
+            12 => Conditional::Endif,
+        };
+        assert_eq!(dead_ranges(&conditionals, &defines()), vec![2..3, 11..12]);
+    }
+
+    #[test]
+    fn finds_guarded_lines() {
+        let conditionals = hashmap! {
+            1 => Conditional::Ifdef(String::from("PLAIN")),
+            3 => Conditional::If(String::from("QUALITY > 1")),
+            5 => Conditional::Endif,
+            6 => Conditional::Else,
+            8 => Conditional::Endif,
+            10 => Conditional::Ifndef(String::from("FANCY")),
+        };
+        assert_eq!(guarded_ranges(&conditionals), vec![4..5, 2..8, 11..usize::MAX]);
+    }
+}
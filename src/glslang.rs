@@ -0,0 +1,132 @@
+//! Validation of rendered trees with the `glslangValidator` reference compiler.
+
+use regex::Regex;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use {GLSLTree, Result};
+
+lazy_static! {
+    static ref DIAGNOSTIC_RE: Regex =
+        Regex::new(r#"^(ERROR|WARNING): \d+:(\d+): (.*)$"#).unwrap();
+}
+
+/// The pipeline stage a shader is validated as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderStage {
+    Vertex,
+    TessControl,
+    TessEvaluation,
+    Geometry,
+    Fragment,
+    Compute,
+}
+
+impl ShaderStage {
+    fn name(self) -> &'static str {
+        match self {
+            ShaderStage::Vertex => "vert",
+            ShaderStage::TessControl => "tesc",
+            ShaderStage::TessEvaluation => "tese",
+            ShaderStage::Geometry => "geom",
+            ShaderStage::Fragment => "frag",
+            ShaderStage::Compute => "comp",
+        }
+    }
+}
+
+/// How serious a diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A message from the validator, located in the original source files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The file the message refers to, or `None` if it refers to a synthesized line.
+    pub path: Option<String>,
+    /// The zero-based line within `path` the message refers to.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl GLSLTree {
+    /// Runs `glslangValidator` (which must be on the `PATH`) over the rendered tree as the
+    /// given stage, returning its located diagnostics mapped back to the files and lines
+    /// they came from. An empty result means the tree validated cleanly.
+    pub fn validate_with_glslang(&self, stage: ShaderStage) -> Result<Vec<Diagnostic>> {
+        let mut child = Command::new("glslangValidator")
+            .args(["--stdin", "-S", stage.name()])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .expect("piped stdin")
+            .write_all(self.render().as_bytes())?;
+        let output = child.wait_with_output()?;
+        Ok(self.parse_diagnostics(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    fn parse_diagnostics(&self, output: &str) -> Vec<Diagnostic> {
+        output
+            .lines()
+            .filter_map(|line| DIAGNOSTIC_RE.captures(line.trim()))
+            .map(|c| {
+                let severity = match &c[1] {
+                    "ERROR" => Severity::Error,
+                    _ => Severity::Warning,
+                };
+                // glslang numbers lines from one, and uses zero for messages without one.
+                let origin = c[2]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|line| line.checked_sub(1))
+                    .and_then(|line| self.origin(line));
+                Diagnostic {
+                    severity,
+                    path: origin.map(|(path, _)| String::from(path)),
+                    line: origin.map(|(_, line)| line),
+                    message: String::from(&c[3]),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn diagnostics_are_remapped() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("tree");
+        let output = "stdin\n\
+                      ERROR: 0:2: 'x' : undeclared identifier\n\
+                      WARNING: 0:1: 'y' : deprecated\n\
+                      ERROR: 1 compilation errors.  No code generated.\n";
+        let diagnostics = tree.parse_diagnostics(output);
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic {
+                    severity: Severity::Error,
+                    path: Some(String::from("src/test_glsl/nested.vert")),
+                    line: Some(0),
+                    message: String::from("'x' : undeclared identifier"),
+                },
+                Diagnostic {
+                    severity: Severity::Warning,
+                    path: None,
+                    line: None,
+                    message: String::from("'y' : deprecated"),
+                },
+            ]
+        );
+    }
+}
@@ -8,12 +8,37 @@
 //! let src_tree = GLSLTree::new("shaders/frag.glsl", include_dirs)?;
 //! ```
 //!
+//! With the `tilde` feature, a leading `~` in the shader path or an include directory
+//! stands for the user's home directory, as in shell-like config files.
+//!
+//! Further options are available through a builder.
+//! ```
+//! let src_tree = GLSLTree::builder("shaders/frag.glsl")
+//!     .include_dirs(&["shaders/include"])
+//!     .hoist_extensions(true)
+//!     .build()?;
+//! ```
+//!
 //! The fully rendered tree is cached in memory and we can retrieve it with
 //! `.render()`.
 //! ```
 //! let src_str = src_tree.render();
 //! ```
 //!
+//! Files included with `#include_optional` are left out if they can't be found, e.g. for
+//! overrides which may or may not be present.
+//! ```glsl
+//! #include_optional "platform.glsl"
+//! ```
+//!
+//! With the `env` feature, `$VAR` and `${VAR}` in include paths and include directories
+//! are replaced with the values of environment variables, e.g. `$SHADER_ROOT/lighting.glsl`.
+//! Referencing an unset variable fails with `Error::UndefinedEnvVar`.
+//!
+//! As in the C preprocessor, a directive ending in a backslash continues onto the next
+//! line. The continued lines are joined onto the directive's line and rendered blank, so
+//! line numbers in the output still match the sources.
+//!
 //! We can refresh the tree if it is expired.
 //! ```
 //! let src_tree = if src_tree.expired()? {
@@ -24,6 +49,8 @@
 //! ```
 //!
 
+#[cfg(feature = "include_dir")]
+extern crate include_dir;
 extern crate itertools;
 extern crate thiserror;
 #[macro_use]
@@ -33,39 +60,251 @@ extern crate lazy_static;
 extern crate maplit;
 extern crate regex;
 extern crate rpds;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_json;
 
+mod builder;
+mod conditional;
+#[cfg(feature = "glslang")]
+mod glslang;
 mod preprocess;
+mod resolver;
 
 use rpds::List;
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::ops::Range;
+use std::fmt;
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
 use thiserror::Error;
 
+use builder::Options;
 use preprocess::AnnotatedGLSL;
 
+pub use builder::{AmbiguityPolicy, GLSLTreeBuilder, NewlineStyle, VersionPolicy};
+pub use conditional::Conditional;
+pub use preprocess::Profile;
+#[cfg(feature = "glslang")]
+pub use glslang::{Diagnostic, Severity, ShaderStage};
+#[cfg(feature = "include_dir")]
+pub use resolver::EmbeddedResolver;
+pub use resolver::{FsResolver, Resolver};
+
 type Result<T> = std::result::Result<T, Error>;
 
+/// The root path of trees whose root source was read from a reader.
+const READER_ROOT: &str = "<reader>";
+
+/// A file in the tree, keyed as in its include directive, and a line index within it.
+type Origin = (String, usize);
+
+/// A line of rendered output and where it came from, if it came from a source line.
+type RenderedLine = (String, Option<Origin>);
+
+/// The flattened sources of a tree, without the lines synthesized ahead of them.
+#[derive(Debug, Clone)]
+struct Body {
+    /// Hoisted `#extension` directives.
+    extensions: Vec<RenderedLine>,
+    lines: Vec<RenderedLine>,
+    /// The files flattened into the body, in the order they were first emitted.
+    files: Vec<String>,
+    /// For each of `files`, the number of lines rendered at the site it was emitted at,
+    /// including the lines of the files it includes and the extensions hoisted from them.
+    site_lines: Vec<usize>,
+}
+
+/// The rendered output of a tree.
+#[derive(Debug, Clone)]
+struct Rendering {
+    text: String,
+    /// The output before the render transform, if one is set.
+    untransformed: Option<String>,
+    /// The file and line each line of the output before any render transform came from;
+    /// `None` for synthesized lines.
+    origins: Vec<Option<Origin>>,
+}
+
+impl Rendering {
+    /// Returns the output which `origins` line up with, before any render transform.
+    fn untransformed(&self) -> &str {
+        self.untransformed.as_ref().unwrap_or(&self.text)
+    }
+}
+
 /// An error loading or refreshing a GLSL source tree.
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("IO Error: {0}")]
     Io(#[from] std::io::Error),
-    #[error("Failed to open {path} after searching {searched_dirs:?}: {cause}")]
     FailedToOpen {
         path: String,
         searched_dirs: Vec<String>,
         cause: std::io::Error,
     },
-    #[error("There is a cycle through these imports: {0}")]
-    Cycle(List<String>),
-    #[error("Versions {root_version:?} and {src_version:?} don't match. See {src_path}")]
+    Cycle {
+        /// The files of the cycle in include order, starting and ending with the file
+        /// included again.
+        cycle: Vec<String>,
+        /// The whole branch being traced when the cycle was found, from the file included
+        /// again back to the root.
+        branch: List<String>,
+    },
     VersionMismatch {
         root_version: usize,
         src_version: usize,
         src_path: String,
     },
-    #[error("Root file is missing")]
     MissingRoot,
+    MisplacedVersion { line: usize },
+    TooManyIncludes { path: String, count: usize },
+    NonUnicodePath { path: PathBuf },
+    MalformedInclude { path: String, line: usize },
+    InvalidIncludeDir { path: String },
+    IncludeEscapesSandbox { path: String },
+    OutsideRequiredDir { path: String, dir: String },
+    IncludesRoot { path: String, line: usize },
+    MtimeUnsupported { path: String },
+    MissingVersion { path: String },
+    DuplicateVersion { path: String, lines: Vec<usize> },
+    FileTooLarge { path: String, size: u64 },
+    EmptyOutput { path: String },
+    BackslashInclude { path: String },
+    /// Only returned with the `env` feature.
+    UndefinedEnvVar { name: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe_error(self, |path| path, f)
+    }
+}
+
+impl Error {
+    /// Returns a displayable form of the error which shows the paths it mentions relative to
+    /// the given base directory (e.g. a project root), for concise messages. Paths outside
+    /// the base are shown as they are. The error itself keeps the paths as given.
+    pub fn relative_to<P: AsRef<Path>>(&self, base: P) -> RelativeError<'_> {
+        RelativeError {
+            error: self,
+            base: base.as_ref().to_path_buf(),
+        }
+    }
+}
+
+/// Something suspicious about a tree which doesn't stop it from rendering. See
+/// `GLSLTree::warnings`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// An included file renders nothing where it is included, because it holds nothing but
+    /// includes of files emitted earlier, or because it is only included in dead
+    /// conditional branches. Such an include may be a mistake.
+    UnreachedInclude { path: String },
+    /// A file's indentation mixes tabs and spaces, first on the given (zero-based) line.
+    /// Only reported if enabled with `GLSLTreeBuilder::warn_mixed_indentation`.
+    MixedIndentation { path: String, line: usize },
+    /// A file declares a version other than the one the tree is rendered as, so code
+    /// written for its version is compiled under another. Not reported in library mode.
+    VersionOverridden {
+        path: String,
+        declared: usize,
+        rendered: usize,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::UnreachedInclude { path } => {
+                write!(f, "{} is included but adds nothing to the output", path)
+            }
+            Warning::MixedIndentation { path, line } => write!(
+                f,
+                "{} mixes tabs and spaces in its indentation on line {}",
+                path,
+                line + 1
+            ),
+            Warning::VersionOverridden {
+                path,
+                declared,
+                rendered,
+            } => write!(
+                f,
+                "{} declares version {} but is rendered as version {}",
+                path, declared, rendered
+            ),
+        }
+    }
+}
+
+/// An error displayed with paths relative to a base directory, as returned by
+/// `Error::relative_to`. Displays like the error itself, but with each path it mentions
+/// shown relative to the base, or as it is if it is outside the base.
+#[derive(Debug)]
+pub struct RelativeError<'a> {
+    error: &'a Error,
+    base: PathBuf,
+}
+
+impl<'a> fmt::Display for RelativeError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe_error(self.error, |path| path.strip_prefix(&self.base).unwrap_or(path), f)
+    }
+}
+
+/// What the tree knows about one of its files. See `GLSLTree::file_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// The absolute path the file was loaded from, or the placeholder path of a root read
+    /// from a reader.
+    pub path: PathBuf,
+    /// The version of the file's version pragma, if it has one.
+    pub version: Option<usize>,
+    /// The file's include paths as written, with the (zero-based) lines they are on, in
+    /// line order.
+    pub includes: Vec<(usize, String)>,
+    /// When the file was last modified as of loading it, if it was loaded from disk.
+    pub mtime: Option<SystemTime>,
+    pub line_count: usize,
+}
+
+/// Counts of the files in a tree. See `GLSLTree::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of files traced.
+    pub traced_count: usize,
+    /// The number of files with lines in the rendered output. Files which are only included
+    /// at sites where they are deduplicated, or which hold nothing but directives the tree
+    /// consumes, don't count.
+    pub emitted_count: usize,
+}
+
+/// A directive the tree recognized in one of its files. See `GLSLTree::directives_of`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectiveInfo {
+    /// The (zero-based) line the directive is on.
+    pub line: usize,
+    pub kind: DirectiveKind,
+}
+
+/// A kind of directive, with its arguments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectiveKind {
+    Version {
+        version: usize,
+        profile: Option<Profile>,
+    },
+    Line {
+        number: usize,
+        source_string: Option<usize>,
+    },
+    /// An include, with its path as written, or after the configured suffix stripping.
+    Include { path: String },
+    Extension { name: String, behavior: String },
+    Conditional(Conditional),
 }
 
 /// An in-memory GLSL source tree.
@@ -74,19 +313,40 @@ pub struct GLSLTree {
     include_dirs: Vec<String>,
     src_map: HashMap<String, AnnotatedGLSL>,
     root_path: String,
-    rendered: String,
+    /// The root source, for trees whose root was not read from a file.
+    root_src: Option<String>,
+    body: OnceLock<Body>,
+    rendered: OnceLock<Rendering>,
+    /// The deepest include nesting reached while tracing.
+    max_depth: usize,
+    options: Options,
 }
 
 impl GLSLTree {
     /// Creates a GLSL source tree from the given glsl file, tracing all its include directives
-    /// and looking for the included files in all given include directories.
+    /// and looking for the included files in all given include directories. If no include
+    /// directories are given, included files are looked for in the root's directory.
     ///
     /// If an include is ambiguous, the first file found will be loaded, so take care of your
     /// include directory order if this applies to you.
+    ///
+    /// Include directories must be valid unicode; construction fails with
+    /// `Error::NonUnicodePath` for any which isn't, rather than skipping it.
     pub fn new<P: AsRef<Path>, P2: AsRef<Path>>(path: P, include_dirs: &[P2]) -> Result<Self> {
         Self::with_default_version(path, include_dirs, 110)
     }
 
+    /// Works like `new`, and also returns the tree's warnings, the same ones `warnings`
+    /// returns, in one call. Only the analyses on by default run; opt into others, such as
+    /// `GLSLTreeBuilder::warn_mixed_indentation`, with `GLSLTreeBuilder::build_with_warnings`.
+    /// The warnings are empty if nothing is suspicious.
+    pub fn new_with_warnings<P: AsRef<Path>, P2: AsRef<Path>>(
+        path: P,
+        include_dirs: &[P2],
+    ) -> Result<(Self, Vec<Warning>)> {
+        Self::builder(path).include_dirs(include_dirs).build_with_warnings()
+    }
+
     /// Works like `new`, except sets the default version. By default OpenGL assumes GLSL
     /// source without a version pragma is version 110. You can pass another default version
     /// to this constructor, but the root source's explicit version pragma if it has one will
@@ -96,171 +356,4041 @@ impl GLSLTree {
         include_dirs: &[P2],
         default_version: usize,
     ) -> Result<Self> {
-        let root_path = match path.as_ref().to_str() {
+        Self::builder(path)
+            .include_dirs(include_dirs)
+            .default_version(default_version)
+            .build()
+    }
+
+    /// Traces the tree rooted at the given glsl file and returns every error in it, rather
+    /// than stopping at the first. See `GLSLTreeBuilder::validate`.
+    pub fn validate_collect<P: AsRef<Path>, P2: AsRef<Path>>(
+        path: P,
+        include_dirs: &[P2],
+    ) -> Result<Vec<Error>> {
+        Self::builder(path).include_dirs(include_dirs).validate()
+    }
+
+    /// Returns a builder for a tree rooted at the given glsl file, for when the defaults of
+    /// `new` don't suit.
+    pub fn builder<P: AsRef<Path>>(path: P) -> GLSLTreeBuilder {
+        GLSLTreeBuilder::new(path)
+    }
+
+    /// Creates a GLSL source tree whose root source is read from the given reader, e.g.
+    /// standard input. Includes are resolved from the include directories as usual.
+    ///
+    /// The root has no file to go stale, so it never counts as expired, and refreshing the
+    /// tree re-traces the includes from the root source read here.
+    pub fn from_reader<R: Read, P: AsRef<Path>>(
+        mut reader: R,
+        include_dirs: &[P],
+        default_version: usize,
+    ) -> Result<Self> {
+        let mut root_src = String::new();
+        reader.read_to_string(&mut root_src)?;
+        let options = Options {
+            default_version,
+            ..Options::default()
+        };
+        Self::build(READER_ROOT, include_dirs, Some(root_src), options)
+    }
+
+    fn build<P: AsRef<Path>, P2: AsRef<Path>>(
+        path: P,
+        include_dirs: &[P2],
+        root_src: Option<String>,
+        mut options: Options,
+    ) -> Result<Self> {
+        if let Some(ref mut sandbox_root) = options.sandbox_root {
+            *sandbox_root = std::fs::canonicalize(&sandbox_root)?;
+        }
+        if let Some(ref mut required_within) = options.required_within {
+            *required_within = std::fs::canonicalize(&required_within)?;
+        }
+        let path: &Path = path.as_ref();
+        #[cfg(feature = "tilde")]
+        let path = &expand_tilde(path, std::env::home_dir());
+        let root_path = match path.to_str() {
             Some(s) => Ok(String::from(s)),
             None => Err(Error::MissingRoot),
         }?;
         let include_dirs: Vec<String> = include_dirs
-            .into_iter()
-            .filter_map(|dir| dir.as_ref().to_str().map(String::from))
-            .collect();
+            .iter()
+            .map(|dir| {
+                let dir: &Path = dir.as_ref();
+                #[cfg(feature = "tilde")]
+                let dir = &expand_tilde(dir, std::env::home_dir());
+                #[cfg(feature = "env")]
+                let dir = &match dir.to_str() {
+                    Some(dir) => PathBuf::from(expand_env_vars(dir, env_var)?),
+                    None => dir.to_path_buf(),
+                };
+                // Rebuilding the path from its components drops trailing and repeated
+                // separators, so files resolved through equivalent spellings of a directory
+                // get equal paths.
+                match dir.components().collect::<PathBuf>().to_str() {
+                    Some(s) => Ok(String::from(s)),
+                    None => Err(Error::NonUnicodePath {
+                        path: dir.to_path_buf(),
+                    }),
+                }
+            })
+            .collect::<Result<_>>()?;
+        if options.validate_include_dirs {
+            if let Some(dir) = include_dirs.iter().find(|dir| !Path::new(dir).is_dir()) {
+                return Err(Error::InvalidIncludeDir { path: dir.clone() });
+            }
+        }
 
-        let src_map = GLSLTree::build_node(
+        let root = match root_src {
+            Some(ref src) => AnnotatedGLSL::parse(src, root_path.clone(), None, &options)?,
+            // root shader; don't search include dirs.
+            None => AnnotatedGLSL::load(&root_path, &Vec::<String>::new(), &options)?,
+        };
+        let search_dirs = GLSLTree::search_dirs(&root_path, &include_dirs, root_src.is_some());
+        let src_map = GLSLTree::trace_node(
             &root_path,
-            &include_dirs,
+            root,
+            &search_dirs,
+            &options,
             &List::new(),
             None,
             HashMap::new(),
         )?;
 
-        let lines = GLSLTree::render_node(
-            &src_map.get(&root_path).unwrap(),
-            &src_map,
-            &mut HashSet::new(),
-        );
-        let version: usize = src_map
-            .get(&root_path)
-            .unwrap()
-            .version_pragma
-            .map(|(_, v)| v)
-            .unwrap_or(default_version);
-        let rendered = vec![version]
-            .into_iter()
-            .map(|v| format!("#version {}", v))
-            .chain(lines.into_iter())
-            .collect::<Vec<String>>()
-            .join("\n");
+        if let Some(ref dir) = options.required_within {
+            let mut includes: Vec<&String> =
+                src_map.keys().filter(|key| **key != root_path).collect();
+            includes.sort();
+            // A root read from a reader has no file to check.
+            let root = if root_src.is_none() { Some(&root_path) } else { None };
+            for key in root.into_iter().chain(includes) {
+                let path = &src_map[key].path;
+                if !std::fs::canonicalize(path)?.starts_with(dir) {
+                    return Err(Error::OutsideRequiredDir {
+                        path: path.clone(),
+                        dir: dir.display().to_string(),
+                    });
+                }
+            }
+        }
 
-        Ok(GLSLTree {
+        // Rendering is deferred to first use, as many trees are only built to list or watch
+        // their dependencies.
+        let tree = GLSLTree {
             include_dirs,
-            rendered,
+            body: OnceLock::new(),
+            rendered: OnceLock::new(),
+            max_depth: GLSLTree::max_traced_depth(&src_map),
+            src_map,
+            root_path,
+            root_src,
+            options,
+        };
+        if tree.options.error_on_empty_output
+            && tree.body().lines.iter().all(|(line, _)| line.trim().is_empty())
+        {
+            return Err(Error::EmptyOutput {
+                path: tree.root_path,
+            });
+        }
+        Ok(tree)
+    }
+
+    /// Returns the directories includes are searched for in.
+    fn search_dirs(root_path: &str, include_dirs: &[String], from_reader: bool) -> Vec<String> {
+        // Without include directories, includes are looked for next to the root. A root
+        // without a parent is in the current directory, where includes are looked for first
+        // anyway.
+        match Path::new(root_path).parent().and_then(Path::to_str) {
+            Some(dir) if include_dirs.is_empty() && !from_reader && !dir.is_empty() => {
+                vec![String::from(dir)]
+            }
+            _ => include_dirs.to_vec(),
+        }
+    }
+
+    /// Returns a tree of the same sources rooted at another file, which is identified by
+    /// its key in this tree (see `directive_offset`), its resolved path, or a path to load
+    /// it from. Sources this tree holds are reused rather than read again, so only files
+    /// which aren't in this tree are loaded. The new tree is rooted at the file's resolved
+    /// path, has the same options, and its version comes from the new root.
+    ///
+    /// Returns `None` if the new root can't be loaded or its tree fails to trace. With a
+    /// `resolve_include` function set, includes may resolve differently depending on the
+    /// including file, so all includes are loaded afresh.
+    pub fn reroot(&self, new_root: &str) -> Option<GLSLTree> {
+        let mut options = self.options.clone();
+        if options.resolve_include.is_none() {
+            options.trace_cache = Some(Arc::new(self.src_map.clone()));
+        }
+        let cached = self
+            .src_map
+            .get(new_root)
+            .or_else(|| self.src_map.values().find(|src| src.path == new_root));
+        let root = match cached {
+            Some(src) => src.clone(),
+            None => AnnotatedGLSL::load(new_root, &[], &options).ok()?,
+        };
+        // A root found by its key is rooted at its resolved path, so it can be loaded again
+        // on refresh.
+        let (root_path, root_src) = if new_root == self.root_path {
+            (String::from(new_root), self.root_src.clone())
+        } else {
+            (root.path.clone(), None)
+        };
+        let search_dirs =
+            GLSLTree::search_dirs(&root_path, &self.include_dirs, root_src.is_some());
+        let src_map = GLSLTree::trace_node(
+            &root_path,
+            root,
+            &search_dirs,
+            &options,
+            &List::new(),
+            None,
+            HashMap::new(),
+        )
+        .ok()?;
+        options.trace_cache = None;
+        Some(GLSLTree {
+            include_dirs: self.include_dirs.clone(),
+            max_depth: GLSLTree::max_traced_depth(&src_map),
             src_map,
             root_path,
+            root_src,
+            body: OnceLock::new(),
+            rendered: OnceLock::new(),
+            options,
         })
     }
 
     /// Refreshes the source tree from disk, re-tracing from the root. Only files
     /// still included in the source tree will be present in the refreshed cache.
     pub fn refresh(self) -> Result<Self> {
-        Self::new(self.root_path, &self.include_dirs)
+        Self::build(self.root_path, &self.include_dirs, self.root_src, self.options)
+    }
+
+    /// Reloads just the root after an edit to its version pragma, and patches the version
+    /// line of the cached render, without reading any included file. If anything else in the
+    /// root changed, e.g. its includes, or the new version could change which includes are
+    /// traced or whether they're valid, the tree is refreshed in full instead. Trees whose
+    /// root wasn't read from a file are left as they are.
+    ///
+    /// If the refresh fails, the error is returned and the tree is left as it was.
+    pub fn refresh_version(&mut self) -> Result<()> {
+        if self.root_src.is_some() {
+            return Ok(());
+        }
+        let root = AnnotatedGLSL::load(&self.root_path, &[], &self.options)?;
+        let old_root = &self.src_map[&self.root_path];
+        let version_only = match (old_root.version_pragma, root.version_pragma) {
+            (Some((old_line, _)), Some((line, version))) => {
+                let children_compatible = self.options.version_policy != VersionPolicy::Strict
+                    || self.options.library_mode
+                    || self.src_map.iter().all(|(path, src)| {
+                        *path == self.root_path
+                            || src.version_pragma.is_none_or(|(_, v)| v == version)
+                    });
+                old_line == line
+                    && old_root.lines.len() == root.lines.len()
+                    && old_root
+                        .lines
+                        .iter()
+                        .zip(&root.lines)
+                        .enumerate()
+                        .all(|(i, (old, new))| i == line || old == new)
+                    && self.options.version_macro.is_none()
+                    && children_compatible
+            }
+            _ => false,
+        };
+        if !version_only {
+            *self = Self::build(
+                self.root_path.clone(),
+                &self.include_dirs,
+                None,
+                self.options.clone(),
+            )?;
+            return Ok(());
+        }
+
+        self.src_map.insert(self.root_path.clone(), root);
+        if self.options.render_transform.is_some() {
+            self.rendered = OnceLock::new();
+        } else if self.options.renders_version_line() {
+            let (version, profile) =
+                GLSLTree::resolved_version(&self.root_path, &self.src_map, &self.options);
+            let newline = self.options.newline_style.as_str();
+            if let Some(rendering) = self.rendered.get_mut() {
+                let end = rendering.text.find(newline).unwrap_or(rendering.text.len());
+                rendering.text.replace_range(..end, &version_directive(version, profile));
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes every include of `from` include `to` instead, without editing any files, and
+    /// re-traces the tree from disk. Paths are include paths as written in directives. The
+    /// remapping lasts for the life of the tree, so later refreshes keep it.
+    ///
+    /// If the re-traced tree is invalid, e.g. because `to` can't be found or introduces a
+    /// cycle, the error is returned and the tree is left as it was.
+    pub fn remap_include(&mut self, from: &str, to: &str) -> Result<()> {
+        let mut options = self.options.clone();
+        options
+            .include_remaps
+            .insert(String::from(from), String::from(to));
+        *self = Self::build(&self.root_path, &self.include_dirs, self.root_src.clone(), options)?;
+        Ok(())
+    }
+
+    /// Replaces the include directories. The tree is not re-traced until the next `refresh`,
+    /// which will search the new directories.
+    pub fn set_include_dirs(&mut self, include_dirs: Vec<String>) {
+        self.include_dirs = include_dirs;
     }
 
     /// Returns whether one or more nodes of the cached source tree are out of sync with
     /// the filesystem.
     pub fn expired(&self) -> Result<bool> {
-        Ok(self
-            .src_map
-            .iter()
-            .map(|(_, ref src)| -> Result<bool> { src.expired() })
-            .collect::<Result<Vec<bool>>>()?
-            .into_iter()
-            .any(|e| e))
+        Ok(!self.expired_files()?.is_empty())
+    }
+
+    /// Returns the paths of the files in the cached source tree which are out of sync with
+    /// the filesystem, sorted.
+    pub fn expired_files(&self) -> Result<Vec<String>> {
+        let mut expired = Vec::new();
+        for src in self.src_map.values() {
+            if src.expired(&self.options)? {
+                expired.push(src.path.clone());
+            }
+        }
+        expired.sort();
+        Ok(expired)
     }
 
     /// Returns the cached source string, whith all includes processed.
     /// This is the result you should feed into your GLSL compiler.
-    pub fn render<'a>(&'a self) -> &'a str {
-        &self.rendered
+    ///
+    /// The tree adds no `#line` directives of its own, but keeps those in the sources
+    /// meaningful: a `#line` directive numbers the lines after it in its own file, so where
+    /// flattening moves those lines, e.g. by expanding an include, a `#line` directive is
+    /// inserted to restore the numbering the original one implies.
+    ///
+    /// The tree is rendered on the first call, and the result is cached until the tree is
+    /// refreshed or its defines are replaced.
+    pub fn render(&self) -> &str {
+        &self.rendering().text
     }
 
-    fn build_node(
-        path: &String,
-        include_dirs: &[String],
-        branch: &List<String>,
-        version: Option<usize>,
-        mut src_map: HashMap<String, AnnotatedGLSL>,
-    ) -> Result<HashMap<String, AnnotatedGLSL>> {
-        let src = if branch.is_empty() {
-            // root shader; don't search include dirs.
-            AnnotatedGLSL::load(path, &Vec::<String>::new())
+    /// Returns the version line the rendered source starts with, e.g. `#version 150 core`,
+    /// or an empty string if version handling is disabled or the tree is built in library
+    /// mode, as no version line is rendered.
+    pub fn version_line(&self) -> &str {
+        if self.options.renders_version_line() {
+            self.render()
+                .split(self.options.newline_style.as_str())
+                .next()
+                .unwrap_or_default()
         } else {
-            AnnotatedGLSL::load(path, &include_dirs)
+            ""
         }
-        .and_then(|src| match (version, src.version_pragma) {
-            (Some(root_version), Some((_, src_version))) if root_version != src_version => {
-                Err(Error::VersionMismatch {
-                    root_version,
-                    src_version,
-                    src_path: path.clone(),
-                })
-            }
-            _ => Ok(src),
-        })?;
+    }
 
-        let version = if branch.is_empty() {
-            // root shader; default GLSL version is 110 if no version pragma.
-            src.version_pragma.map(|(_, v)| v).or(Some(110))
-        } else {
-            version
+    /// Writes the rendered source into the given buffer, replacing its contents. Reusing a
+    /// buffer avoids allocating a new string each time the tree is rendered.
+    pub fn render_into(&self, buf: &mut String) {
+        buf.clear();
+        buf.push_str(self.render());
+    }
+
+    /// Writes the rendered source to the given sink, producing the same bytes as `render`.
+    /// The flattened lines are still collected first, as hoisted extensions go ahead of
+    /// them, but they are written one by one rather than joined into a single string, and
+    /// the render cache is neither consulted nor filled unless it is already filled or a
+    /// render transform needs the whole output.
+    pub fn render_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        if let Some(rendering) = self.rendered.get() {
+            return w.write_all(rendering.text.as_bytes());
+        }
+        if self.options.render_transform.is_some() {
+            return w.write_all(self.render().as_bytes());
+        }
+        let rendered_body;
+        let body = match self.body.get() {
+            Some(body) => body,
+            None => {
+                rendered_body =
+                    GLSLTree::render_body(&self.root_path, &self.src_map, &self.options);
+                &rendered_body
+            }
         };
+        let lines = GLSLTree::output_lines(&self.root_path, &self.src_map, &self.options, body);
+        for (i, (line, _)) in lines.enumerate() {
+            if i > 0 {
+                w.write_all(self.options.newline_style.as_str().as_bytes())?;
+            }
+            w.write_all(line.as_bytes())?;
+        }
+        Ok(())
+    }
 
-        let branch = branch.push_front(path.clone());
-        let include_files = src
-            .includes
-            .clone()
-            .into_iter()
-            .map(|(_, v)| v)
-            .map(|included_file| {
-                if branch.iter().any(|p| included_file == *p) {
-                    Err(Error::Cycle(branch.push_front(included_file.clone())))
-                } else {
-                    Ok(included_file)
+    /// Assembles the raw sources of the tree, as kept with `GLSLTreeBuilder::preserve_raw`,
+    /// or returns `None` if they weren't kept, as for a root read by `from_reader` or a tree
+    /// with a source transform. Unlike `render`, which normalizes its output, this reproduces
+    /// each file byte for byte, line endings, version pragmas and all, except for its include
+    /// directives: each include directive, with any lines it continues onto, up to its line
+    /// ending, is replaced by the included file's raw source less one final line ending where
+    /// `render` expands it, and by nothing elsewhere. A tree without includes renders as its
+    /// root exactly.
+    pub fn render_raw(&self) -> Option<String> {
+        let mut out = String::new();
+        let mut seen = HashSet::new();
+        self.render_raw_node(&self.root_path, &mut seen, &mut out)?;
+        Some(out)
+    }
+
+    fn render_raw_node(
+        &self,
+        path: &str,
+        seen: &mut HashSet<String>,
+        out: &mut String,
+    ) -> Option<()> {
+        let src = &self.src_map[path];
+        let raw = src.raw.as_ref()?;
+        let dead = self.options.dead_ranges(&src.conditionals, self.version());
+        let lines: Vec<&str> = raw.split_inclusive('\n').collect();
+        let mut continued = 0..0;
+        for (i, line) in lines.iter().enumerate() {
+            if continued.contains(&i) {
+                continue;
+            }
+            let include = match src.includes.get(&i) {
+                Some(include) => include,
+                None => {
+                    out.push_str(line);
+                    continue;
                 }
-            })
-            .collect::<Result<Vec<String>>>()?;
-        src_map.insert(path.clone(), src);
-        include_files.into_iter().fold(
-            Ok(src_map),
-            move |src_map_r: Result<HashMap<_, _>>,
-                  included_file: String|
-                  -> Result<HashMap<_, _>> {
-                let branch = branch.clone();
-                src_map_r.and_then(move |src_map| {
-                    GLSLTree::build_node(&included_file, include_dirs, &branch, version, src_map)
-                })
+            };
+            let continues = |line: &str| line.trim_end_matches(['\r', '\n']).ends_with('\\');
+            let last = (i..lines.len())
+                .find(|&j| !continues(lines[j]) || j + 1 == lines.len())
+                .unwrap_or(i);
+            continued = (i + 1)..(last + 1);
+            let line = lines[last];
+            let ending = if line.ends_with("\r\n") {
+                "\r\n"
+            } else if line.ends_with('\n') {
+                "\n"
+            } else {
+                ""
+            };
+            let expanded = !dead.iter().any(|range| range.contains(&i))
+                && self.options.follows_include(include, path)
+                && self.src_map.contains_key(include)
+                && seen.insert(include.clone());
+            if expanded {
+                let start = out.len();
+                self.render_raw_node(include, seen, out)?;
+                if !ending.is_empty() {
+                    if out[start..].ends_with("\r\n") {
+                        out.truncate(out.len() - 2);
+                    } else if out[start..].ends_with('\n') {
+                        out.truncate(out.len() - 1);
+                    }
+                }
+            }
+            out.push_str(ending);
+        }
+        Some(())
+    }
+
+    /// Returns the rendered source without its leading `#version` line, for compilers which
+    /// are given the version separately. Version pragmas of the sources are still stripped,
+    /// and the render transform, if any, is applied to the source without the version line.
+    pub fn render_without_version(&self) -> String {
+        let newline = self.options.newline_style.as_str();
+        match self.options.render_transform {
+            None if !self.options.renders_version_line() => String::from(self.render()),
+            None => match self.render().split_once(newline) {
+                Some((_, rest)) => String::from(rest),
+                None => String::new(),
             },
+            Some(ref transform) => {
+                let lines: Vec<_> = GLSLTree::output_lines(
+                    &self.root_path,
+                    &self.src_map,
+                    &self.options,
+                    self.body(),
+                )
+                .skip(usize::from(self.options.renders_version_line()))
+                .map(|(line, _)| line)
+                .collect();
+                (transform.0)(lines.join(newline))
+            }
+        }
+    }
+
+    /// Returns the rendered source normalized for comparison against snapshots: lines end in
+    /// `\n` whatever the newline style, carry no trailing whitespace, and the output ends in
+    /// exactly one newline. Use `render` for the output exactly as flattened.
+    pub fn render_canonical(&self) -> String {
+        let mut canonical = self
+            .render()
+            .lines()
+            .map(str::trim_end)
+            .collect::<Vec<_>>()
+            .join("\n")
+            .trim_end()
+            .to_string();
+        canonical.push('\n');
+        canonical
+    }
+
+    /// Returns a hash of the rendered output, which includes its version line, for use as a
+    /// cache key. The hash is 64-bit FNV-1a, so it is the same across runs, platforms and
+    /// versions of this crate.
+    pub fn content_hash(&self) -> u64 {
+        fnv1a(self.render().as_bytes())
+    }
+
+    /// Returns the rendered source with a comment after the version line recording the files
+    /// it was generated from, for shipping as a self-contained shader, e.g.
+    ///
+    /// ```glsl
+    /// #version 150
+    /// // Generated from: shaders/frag.glsl including common.glsl, lighting.glsl
+    /// ```
+    ///
+    /// Files are listed in the order they are emitted. The comment deliberately carries no
+    /// timestamp so exports of unchanged trees are identical.
+    pub fn export(&self) -> String {
+        let body = self.body();
+        let mut files = body
+            .files
+            .iter()
+            .map(|path| self.src_map[path].portable_path.as_str());
+        let mut header = format!("// Generated from: {}", files.next().unwrap());
+        let includes: Vec<&str> = files.collect();
+        if !includes.is_empty() {
+            header.push_str(" including ");
+            header.push_str(&includes.join(", "));
+        }
+
+        let rendered = self.render();
+        let newline = self.options.newline_style.as_str();
+        if self.options.renders_version_line() {
+            let version_end = rendered.find(newline).unwrap_or(rendered.len());
+            let (version, rest) = rendered.split_at(version_end);
+            format!("{}{}{}{}", version, newline, header, rest)
+        } else {
+            format!("{}{}{}", header, newline, rendered)
+        }
+    }
+
+    /// Returns a Make-style depfile rule, for Make and Ninja, declaring that the given build
+    /// target depends on every file in the tree: the root, then the included files sorted
+    /// by path. Spaces, `#` and `$` in paths are escaped as Make expects.
+    pub fn write_depfile(&self, output_target: &str) -> String {
+        let mut includes: Vec<&str> = self
+            .src_map
+            .iter()
+            .filter(|(key, _)| **key != self.root_path)
+            .map(|(_, src)| src.portable_path.as_str())
+            .collect();
+        includes.sort();
+        includes.dedup();
+        let root = &self.src_map[&self.root_path];
+        let dependencies = Some(root.portable_path.as_str())
+            .filter(|_| root.mtime.is_some())
+            .into_iter()
+            .chain(includes)
+            .map(escape_make_path);
+        let mut depfile = format!("{}:", escape_make_path(output_target));
+        for dependency in dependencies {
+            depfile.push(' ');
+            depfile.push_str(&dependency);
+        }
+        depfile.push('\n');
+        depfile
+    }
+
+    /// Frees the cached render. The tree keeps its sources, so a later call to `render` or
+    /// any other method which needs the rendered output will render it again and re-cache
+    /// it. This is useful for long lived trees which are rendered rarely.
+    pub fn drop_rendered(&mut self) {
+        self.body = OnceLock::new();
+        self.rendered = OnceLock::new();
+    }
+
+    /// Renders the tree from its cached sources without consulting or filling the render
+    /// cache. The result is identical to `render`.
+    pub fn render_uncached(&self) -> String {
+        let body = GLSLTree::render_body(&self.root_path, &self.src_map, &self.options);
+        GLSLTree::render_tree(&self.root_path, &self.src_map, &self.options, &body).text
+    }
+
+    /// Replaces the `#define`s injected after the version line. Only the injected block is
+    /// re-rendered; no files are read or re-traced, which makes rendering many variants of
+    /// one tree cheap.
+    ///
+    /// With `GLSLTreeBuilder::prune_conditionals`, trees with conditional directives are
+    /// re-flattened with the new defines, but files which only appear in branches that were
+    /// dead when the tree was traced are not loaded; `refresh` the tree to trace them.
+    pub fn set_defines<S: AsRef<str>, S2: AsRef<str>>(&mut self, defines: &[(S, S2)]) {
+        self.options.defines = builder::owned_defines(defines);
+        if self.options.prune_conditionals
+            && self.src_map.values().any(|src| !src.conditionals.is_empty())
+        {
+            self.body = OnceLock::new();
+        }
+        self.rendered = OnceLock::new();
+    }
+
+    fn body(&self) -> &Body {
+        self.body
+            .get_or_init(|| GLSLTree::render_body(&self.root_path, &self.src_map, &self.options))
+    }
+
+    fn rendering(&self) -> &Rendering {
+        self.rendered.get_or_init(|| {
+            GLSLTree::render_tree(&self.root_path, &self.src_map, &self.options, self.body())
+        })
+    }
+
+    /// Returns the version of the rendered output: that of the root's version pragma, or the
+    /// default version if it has none. Under `VersionPolicy::Highest`, a higher version
+    /// declared by an included file wins.
+    pub fn version(&self) -> usize {
+        GLSLTree::resolved_version(&self.root_path, &self.src_map, &self.options).0
+    }
+
+    /// Returns the version assumed for a root without a version pragma, whether or not the
+    /// root has one. When it differs from `version`, the version came from the root.
+    pub fn default_version(&self) -> usize {
+        self.options.default_version
+    }
+
+    /// Returns whether the tree is GLSL ES, going by the root's version pragma, or the
+    /// default version and profile if it has none. Version 100 is always ES, and any other
+    /// version is ES only with the `es` profile, as in `#version 300 es`.
+    pub fn is_es(&self) -> bool {
+        matches!(
+            GLSLTree::resolved_version(&self.root_path, &self.src_map, &self.options),
+            (100, _) | (_, Some(Profile::Es))
         )
     }
 
-    fn render_node(
-        src: &AnnotatedGLSL,
-        src_map: &HashMap<String, AnnotatedGLSL>,
-        seen: &mut HashSet<String>,
-    ) -> Vec<String> {
-        src.lines
+    /// Returns, for each file flattened into the rendered output, the number of lines it
+    /// contributes, in the order the files are first emitted. Directives the tree consumes,
+    /// such as includes and version pragmas, don't count, and a file included more than once
+    /// only counts once, as it is only emitted once.
+    pub fn line_contributions(&self) -> Vec<(String, usize)> {
+        let body = self.body();
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (path, _) in body.extensions.iter().chain(&body.lines).flat_map(|(_, o)| o) {
+            *counts.entry(path.as_str()).or_insert(0) += 1;
+        }
+        body.files
             .iter()
-            .enumerate()
-            .map(|(i, line)| {
-                if let Some((path, ref src)) = src
-                    .includes
-                    .get(&i)
-                    .and_then(|path| src_map.get(path).map(|src| (path, src)))
-                {
-                    if seen.contains(path) {
-                        None
-                    } else {
-                        seen.insert(path.clone());
-                        Some(GLSLTree::render_node(src, src_map, seen))
-                    }
-                } else if let Some(true) = src.version_pragma.map(|(j, _)| j == i) {
-                    None
-                } else {
-                    Some(vec![line.clone()])
-                }
-            })
-            .filter_map(|v| v)
-            .flat_map(|v| v)
+            .map(|path| (path.clone(), counts.get(path.as_str()).cloned().unwrap_or(0)))
             .collect()
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// Returns the number of files in the tree, the root counting as one, e.g. for
+    /// bookkeeping over many trees. Files included several times are counted once.
+    pub fn len(&self) -> usize {
+        self.src_map.len()
+    }
 
-    #[test]
-    fn it_works() {
-        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
-        println!("render: {}", tree.render());
+    /// Returns whether the tree holds no files, which is never the case once it is built, as
+    /// it holds at least the root.
+    pub fn is_empty(&self) -> bool {
+        self.src_map.is_empty()
+    }
+
+    /// Returns the deepest include nesting reached while tracing the tree, the root being at
+    /// depth 0 and the files it includes at depth 1, e.g. for judging whether to flatten an
+    /// include structure.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    fn max_traced_depth(src_map: &HashMap<String, AnnotatedGLSL>) -> usize {
+        src_map.values().map(|src| src.depth).max().unwrap_or(0)
+    }
+
+    /// Returns counts of the files in the tree. When fewer files are emitted than traced,
+    /// some were deduplicated or contribute no lines of their own.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            traced_count: self.src_map.len(),
+            emitted_count: self
+                .line_contributions()
+                .iter()
+                .filter(|(_, count)| *count > 0)
+                .count(),
+        }
+    }
+
+    /// Returns warnings about the tree, which are computed on each call. Included files
+    /// are identified by their keys in the tree (see `directive_offset`).
+    pub fn warnings(&self) -> Vec<Warning> {
+        let body = self.body();
+        let mut unreached: Vec<&String> = self
+            .src_map
+            .keys()
+            .filter(|key| **key != self.root_path)
+            .filter(|key| match body.files.iter().position(|file| file == *key) {
+                Some(i) => body.site_lines[i] == 0,
+                None => true,
+            })
+            .collect();
+        unreached.sort();
+        let mut warnings: Vec<Warning> = unreached
+            .into_iter()
+            .map(|path| Warning::UnreachedInclude { path: path.clone() })
+            .collect();
+        let mut includes: Vec<&String> =
+            self.src_map.keys().filter(|key| **key != self.root_path).collect();
+        includes.sort();
+        if !self.options.library_mode {
+            let rendered = self.version();
+            warnings.extend(
+                std::iter::once(&self.root_path)
+                    .chain(includes.iter().cloned())
+                    .filter_map(|path| match self.src_map[path].version_pragma {
+                        Some((_, declared)) if declared != rendered => {
+                            Some(Warning::VersionOverridden {
+                                path: path.clone(),
+                                declared,
+                                rendered,
+                            })
+                        }
+                        _ => None,
+                    }),
+            );
+        }
+        if self.options.warn_mixed_indentation {
+            warnings.extend(std::iter::once(&self.root_path).chain(includes).filter_map(
+                |path| {
+                    self.src_map[path]
+                        .mixed_indentation_line()
+                        .map(|line| Warning::MixedIndentation {
+                            path: path.clone(),
+                            line,
+                        })
+                },
+            ));
+        }
+        warnings
+    }
+
+    /// Returns whether the given file is emitted into the rendered output, i.e. whether
+    /// editing it could change the output. Files are identified by their key in the tree
+    /// (see `directive_offset`) or by their resolved path.
+    ///
+    /// A file included several times is emitted at its first include site, so it affects
+    /// the output even though later includes of it are deduplicated. Files which were traced
+    /// but aren't emitted, like those left only in dead conditional branches by
+    /// `set_defines`, don't.
+    pub fn affects_output(&self, path: &str) -> bool {
+        self.body()
+            .files
+            .iter()
+            .any(|file| file == path || self.src_map[file].path == path)
+    }
+
+    /// Maps a (zero-based) line of the rendered output back to the file it came from and
+    /// the (zero-based) line within that file. Returns `None` for lines the tree
+    /// synthesized, such as the version line, and for lines past the end of the output.
+    /// Lines are counted in the output before any render transform.
+    ///
+    /// File paths are given with forward slashes on all platforms.
+    pub fn origin(&self, line: usize) -> Option<(&str, usize)> {
+        self.rendering()
+            .origins
+            .get(line)
+            .and_then(|origin| origin.as_ref())
+            .map(|(path, line)| (self.src_map[path].portable_path.as_str(), *line))
+    }
+
+    /// Returns a chain of files from the root to the given file, each including the next,
+    /// explaining why the file is in the tree. Files are identified by their key in the
+    /// tree (see `directive_offset`), and the target may also be given by its resolved path.
+    /// When there are several chains, the first in include order is returned. Returns
+    /// `None` if the tree doesn't hold the file.
+    pub fn include_path_to(&self, path: &str) -> Option<Vec<String>> {
+        let mut chain = vec![self.root_path.clone()];
+        if self.find_include_path(path, &mut chain, &mut HashSet::new()) {
+            Some(chain)
+        } else {
+            None
+        }
+    }
+
+    fn find_include_path(
+        &self,
+        target: &str,
+        chain: &mut Vec<String>,
+        visited: &mut HashSet<String>,
+    ) -> bool {
+        let key = chain.last().unwrap().clone();
+        let src = &self.src_map[&key];
+        if key == target || src.path == target {
+            return true;
+        }
+        visited.insert(key.clone());
+        let dead = self.options.dead_ranges(&src.conditionals, self.version());
+        let mut includes: Vec<(&usize, &String)> = src
+            .includes
+            .iter()
+            .filter(|(i, include)| {
+                !dead.iter().any(|range| range.contains(i)) && self.src_map.contains_key(*include)
+            })
+            .filter(|(_, include)| self.options.follows_include(include, &key))
+            .collect();
+        includes.sort();
+        for (_, include) in includes {
+            if !visited.contains(include) {
+                chain.push(include.clone());
+                if self.find_include_path(target, chain, visited) {
+                    return true;
+                }
+                chain.pop();
+            }
+        }
+        false
+    }
+
+    /// Returns the files both trees hold, identified by their keys (see `directive_offset`),
+    /// sorted. For trees of several stages of one program, these are the headers the stages
+    /// share.
+    pub fn shared_dependencies(&self, other: &GLSLTree) -> Vec<String> {
+        let mut shared: Vec<String> = self
+            .src_map
+            .keys()
+            .filter(|key| other.src_map.contains_key(*key))
+            .cloned()
+            .collect();
+        shared.sort();
+        shared
+    }
+
+    /// Returns an iterator over the lines of the rendered output which came from the
+    /// sources, with the file and (zero-based) line each came from, as given by `origin`.
+    /// Lines the tree synthesized, such as the version line, are skipped, and lines are
+    /// given as they are before any render transform.
+    pub fn rendered_lines_with_origin(&self) -> impl Iterator<Item = (&str, &str, usize)> {
+        let rendering = self.rendering();
+        rendering
+            .untransformed()
+            .split(self.options.newline_style.as_str())
+            .zip(&rendering.origins)
+            .filter_map(move |(line, origin)| {
+                origin
+                    .as_ref()
+                    .map(|(path, i)| (line, self.src_map[path].portable_path.as_str(), *i))
+            })
+    }
+
+    /// Returns the rendered output split into chunks, one for each run of lines from the
+    /// same file and one for each run of lines the tree synthesized, such as the version
+    /// line and injected defines, which are grouped apart from file lines. Each chunk but
+    /// the last ends with a newline, so the chunks concatenate to `render`, e.g. for shader
+    /// APIs which take source as an array of strings. A render transform may rearrange
+    /// lines arbitrarily, so its output is returned as one chunk.
+    pub fn render_chunks(&self) -> Vec<String> {
+        let rendering = self.rendering();
+        if rendering.untransformed.is_some() {
+            return vec![rendering.text.clone()];
+        }
+        let newline = self.options.newline_style.as_str();
+        let mut chunks: Vec<String> = Vec::new();
+        let mut chunk_file = None;
+        for (line, origin) in rendering.text.split(newline).zip(&rendering.origins) {
+            let file = origin.as_ref().map(|(path, _)| path);
+            if let Some(chunk) = chunks.last_mut() {
+                chunk.push_str(newline);
+                if chunk_file == Some(file) {
+                    chunk.push_str(line);
+                    continue;
+                }
+            }
+            chunks.push(String::from(line));
+            chunk_file = Some(file);
+        }
+        chunks
+    }
+
+    /// Returns the byte offset into the given file at which the directive on the given
+    /// (zero-based) line begins, or `None` if that line holds no recognized directive.
+    ///
+    /// Files are identified as they are keyed in the tree: the root by the path the tree
+    /// was constructed with, and includes by the path written in their include directive.
+    pub fn directive_offset(&self, path: &str, line: usize) -> Option<usize> {
+        self.src_map
+            .get(path)
+            .and_then(|src| src.directive_offsets.get(&line).cloned())
+    }
+
+    /// Returns the byte range of the include path (excluding its delimiters) of the
+    /// include directive on the given (zero-based) line of the given file, so the path
+    /// can be rewritten in place.
+    pub fn include_span(&self, path: &str, line: usize) -> Option<Range<usize>> {
+        self.src_map
+            .get(path)
+            .and_then(|src| src.include_spans.get(&line).cloned())
+    }
+
+    /// Returns the extensions required by `#extension` directives anywhere in the emitted
+    /// files, as names and behaviors, in the order they are first rendered. An extension
+    /// declared more than once is listed once, with the behavior it is first declared with.
+    pub fn required_extensions(&self) -> Vec<(String, String)> {
+        let body = self.body();
+        let mut required: Vec<(String, String)> = Vec::new();
+        for (path, i) in body.extensions.iter().chain(&body.lines).flat_map(|(_, o)| o) {
+            if let Some(extension) = self.src_map[path].extensions.get(i) {
+                if !required.iter().any(|(name, _)| *name == extension.0) {
+                    required.push(extension.clone());
+                }
+            }
+        }
+        required
+    }
+
+    /// Returns the files and (zero-based) lines of directives the tree passes through to the
+    /// compiler untouched, with their keywords: `#define`, `#undef`, `#error` and the
+    /// conditionals. Macros aren't expanded and conditional branches are all rendered, even
+    /// where conditionals decided which includes were traced, so shaders relying on them are
+    /// left to the compiler to resolve. Files are identified by their keys in the tree (see
+    /// `directive_offset`), the root first and the rest in order.
+    pub fn uses_unsupported_preprocessor(&self) -> Vec<(String, usize, String)> {
+        let mut includes: Vec<&String> =
+            self.src_map.keys().filter(|key| **key != self.root_path).collect();
+        includes.sort();
+        std::iter::once(&self.root_path)
+            .chain(includes)
+            .flat_map(|path| {
+                self.src_map[path]
+                    .passthrough_directives()
+                    .into_iter()
+                    .map(move |(line, keyword)| (path.clone(), line, keyword))
+            })
+            .collect()
+    }
+
+    /// Returns the path an include of the given path, as written in a directive, would be
+    /// loaded from, without loading it, or `None` if nothing matches. `from` is the including
+    /// file as keyed in the tree (see `directive_offset`), consulted by a `resolve_include`
+    /// function; it defaults to the root. The include needn't be in the tree.
+    pub fn resolve(&self, include: &str, from: Option<&str>) -> Option<String> {
+        let include = preprocess::normalize_include(String::from(include), &self.options);
+        #[cfg(feature = "env")]
+        let include = expand_env_vars(&include, env_var).ok()?;
+        let includer = from.unwrap_or(&self.root_path);
+        match GLSLTree::resolve_ahead_of_search(&include, includer, &self.options) {
+            Some(file) => AnnotatedGLSL::locate(&file, &[], &self.options),
+            None => {
+                let search_dirs = GLSLTree::search_dirs(
+                    &self.root_path,
+                    &self.include_dirs,
+                    self.root_src.is_some(),
+                );
+                AnnotatedGLSL::locate(&include, &search_dirs, &self.options)
+            }
+        }
+    }
+
+    /// Returns the path the include directive on the given (zero-based) line of the given
+    /// file resolved to, or `None` if that line holds no include the tree traced. Includes
+    /// which resolved to the same path share a file, however they were written.
+    pub fn resolved_path_of(&self, including_file: &str, line: usize) -> Option<&str> {
+        self.src_map
+            .get(including_file)
+            .and_then(|src| src.includes.get(&line))
+            .and_then(|include| self.src_map.get(include))
+            .map(|src| src.path.as_str())
+    }
+
+    /// Returns what the tree knows about the given file, identified by its key in the tree
+    /// (see `directive_offset`) or by its resolved path, or `None` if the tree doesn't
+    /// hold it.
+    pub fn file_info(&self, path: &str) -> Option<FileInfo> {
+        let src = self
+            .src_map
+            .get(path)
+            .or_else(|| self.src_map.values().find(|src| src.path == path))?;
+        let mut includes: Vec<(usize, String)> = src
+            .includes
+            .iter()
+            .map(|(line, include)| (*line, include.clone()))
+            .collect();
+        includes.sort();
+        Some(FileInfo {
+            path: match src.mtime {
+                Some(_) => std::path::absolute(&src.path).ok()?,
+                None => PathBuf::from(&src.path),
+            },
+            version: src.version_pragma.map(|(_, version)| version),
+            includes,
+            mtime: src.mtime,
+            line_count: src.lines.len(),
+        })
+    }
+
+    /// Returns a JSON description of the tree for external tools, such as editors showing
+    /// dependencies. The schema is stable:
+    ///
+    /// ```json
+    /// {
+    ///   "root": "shaders/frag.glsl",
+    ///   "version": 330,
+    ///   "profile": "core",
+    ///   "files": [
+    ///     {
+    ///       "key": "shaders/frag.glsl",
+    ///       "path": "shaders/frag.glsl",
+    ///       "version": 330,
+    ///       "line_count": 12,
+    ///       "includes": [{ "line": 1, "path": "common.glsl" }]
+    ///     }
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// `version` and `profile` are those of the rendered output; `profile` is `null` if it
+    /// names none. `files` lists each file in the tree, the root first and the rest sorted
+    /// by key, where `key` identifies it as `directive_offset` does and `path` is where it
+    /// was resolved to. A file's `version` is that of its version pragma, or `null`.
+    /// `includes` lists its include directives with their (zero-based) lines and paths as
+    /// written, in line order; those the tree traced are keyed by that path in `files`.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        let (version, profile) =
+            GLSLTree::resolved_version(&self.root_path, &self.src_map, &self.options);
+        let mut includes: Vec<&String> =
+            self.src_map.keys().filter(|key| **key != self.root_path).collect();
+        includes.sort();
+        let files: Vec<serde_json::Value> = std::iter::once(&self.root_path)
+            .chain(includes)
+            .map(|key| {
+                let src = &self.src_map[key];
+                let mut includes: Vec<(&usize, &String)> = src.includes.iter().collect();
+                includes.sort();
+                json!({
+                    "key": key,
+                    "path": src.portable_path,
+                    "version": src.version_pragma.map(|(_, version)| version),
+                    "line_count": src.lines.len(),
+                    "includes": includes
+                        .into_iter()
+                        .map(|(line, path)| json!({ "line": line, "path": path }))
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        json!({
+            "root": self.root_path,
+            "version": version,
+            "profile": profile.map(|profile| profile.to_string()),
+            "files": files,
+        })
+        .to_string()
+    }
+
+    /// Returns the directives recognized in the given file, identified by its key in the
+    /// tree (see `directive_offset`) or by its resolved path, in line order, or `None` if the
+    /// tree doesn't hold the file. Version pragmas are only recognized while version
+    /// handling is enabled.
+    pub fn directives_of(&self, path: &str) -> Option<Vec<DirectiveInfo>> {
+        let src = self
+            .src_map
+            .get(path)
+            .or_else(|| self.src_map.values().find(|src| src.path == path))?;
+        let version = src.version_pragma.map(|(line, version)| DirectiveInfo {
+            line,
+            kind: DirectiveKind::Version {
+                version,
+                profile: src.profile,
+            },
+        });
+        let includes = src.includes.iter().map(|(line, path)| DirectiveInfo {
+            line: *line,
+            kind: DirectiveKind::Include { path: path.clone() },
+        });
+        let extensions = src
+            .extensions
+            .iter()
+            .map(|(line, (name, behavior))| DirectiveInfo {
+                line: *line,
+                kind: DirectiveKind::Extension {
+                    name: name.clone(),
+                    behavior: behavior.clone(),
+                },
+            });
+        let line_directives = src
+            .line_directives
+            .iter()
+            .map(|(line, (number, source_string))| DirectiveInfo {
+                line: *line,
+                kind: DirectiveKind::Line {
+                    number: *number,
+                    source_string: *source_string,
+                },
+            });
+        let conditionals = src.conditionals.iter().map(|(line, conditional)| DirectiveInfo {
+            line: *line,
+            kind: DirectiveKind::Conditional(conditional.clone()),
+        });
+        let mut directives: Vec<DirectiveInfo> = version
+            .into_iter()
+            .chain(includes)
+            .chain(extensions)
+            .chain(line_directives)
+            .chain(conditionals)
+            .collect();
+        directives.sort_by_key(|directive| directive.line);
+        Some(directives)
+    }
+
+    fn build_node(
+        path: &str,
+        optional: bool,
+        include_dirs: &[String],
+        options: &Options,
+        branch: &List<String>,
+        version: Option<usize>,
+        src_map: HashMap<String, AnnotatedGLSL>,
+    ) -> Result<HashMap<String, AnnotatedGLSL>> {
+        let load = |src_map| {
+            GLSLTree::load_node(path, optional, include_dirs, options, branch, version, src_map)
+        };
+        match options.error_sink {
+            Some(ref errors) => {
+                let traced = src_map.clone();
+                match load(src_map) {
+                    Err(error) => {
+                        errors.lock().unwrap().push(error);
+                        Ok(traced)
+                    }
+                    src_map => src_map,
+                }
+            }
+            None => load(src_map),
+        }
+    }
+
+    fn load_node(
+        path: &str,
+        optional: bool,
+        include_dirs: &[String],
+        options: &Options,
+        branch: &List<String>,
+        version: Option<usize>,
+        src_map: HashMap<String, AnnotatedGLSL>,
+    ) -> Result<HashMap<String, AnnotatedGLSL>> {
+        let includer = branch.first().map(String::as_str).unwrap_or_default();
+        // The file is keyed by its include path as written, but resolved after expansion.
+        #[cfg(feature = "env")]
+        let target = &expand_env_vars(path, env_var)?;
+        #[cfg(not(feature = "env"))]
+        let target = path;
+        let resolved = GLSLTree::resolve_ahead_of_search(target, includer, options);
+        let cached = options.trace_cache.as_ref().and_then(|cache| cache.get(path));
+        let loaded = match (cached, resolved) {
+            (Some(src), _) => Ok(src.clone()),
+            (None, Some(file)) => AnnotatedGLSL::load(&file, &[], options),
+            (None, None) => AnnotatedGLSL::load(target, include_dirs, options),
+        };
+        let src = match loaded {
+            // An optional include of a file which can't be found is left out of the tree.
+            Err(Error::FailedToOpen { .. }) if optional => return Ok(src_map),
+            Err(Error::FailedToOpen { .. }) if !cfg!(windows) && target.contains('\\') => {
+                return Err(Error::BackslashInclude {
+                    path: path.to_string(),
+                })
+            }
+            loaded => loaded?,
+        };
+        if let Some(ref sandbox_root) = options.sandbox_root {
+            if !std::fs::canonicalize(&src.path)?.starts_with(sandbox_root) {
+                return Err(Error::IncludeEscapesSandbox { path: src.path });
+            }
+        }
+        // A back edge to an ancestor included under another spelling of its path.
+        if options.allow_back_edges && branch.iter().any(|p| src_map[p].path == src.path) {
+            return Ok(src_map);
+        }
+        if let Some(root) = branch.last() {
+            if src_map[root].path == src.path {
+                let line = src_map[includer]
+                    .includes
+                    .iter()
+                    .filter(|(_, include)| *include == path)
+                    .map(|(line, _)| *line)
+                    .min()
+                    .unwrap_or(0);
+                return Err(Error::IncludesRoot {
+                    path: String::from(includer),
+                    line,
+                });
+            }
+        }
+        GLSLTree::trace_node(path, src, include_dirs, options, branch, version, src_map)
+    }
+
+    /// Returns the file an include resolves to ahead of the include directories: through
+    /// the manifest, the include overrides, the `resolve_include` function or the module
+    /// roots, in that order, if any of them resolves it.
+    fn resolve_ahead_of_search(include: &str, includer: &str, options: &Options) -> Option<String> {
+        options
+            .manifest
+            .get(include)
+            .and_then(|file| file.to_str())
+            .map(String::from)
+            .or_else(|| options.include_overrides.get(include).cloned())
+            .or_else(|| {
+                options
+                    .resolve_include
+                    .as_ref()
+                    .and_then(|resolve| (resolve.0)(include, includer))
+            })
+            .or_else(|| {
+                // The most specific of the module roots holding the include wins.
+                let mut module_roots: Vec<&PathBuf> = options.module_roots.iter().collect();
+                module_roots.sort_by_key(|root| std::cmp::Reverse(root.components().count()));
+                module_roots.into_iter().find_map(|root| {
+                    let candidate = root.join(include);
+                    AnnotatedGLSL::locate(candidate.to_str()?, &[], options)
+                })
+            })
+    }
+
+    fn trace_node(
+        path: &str,
+        mut src: AnnotatedGLSL,
+        include_dirs: &[String],
+        options: &Options,
+        branch: &List<String>,
+        version: Option<usize>,
+        mut src_map: HashMap<String, AnnotatedGLSL>,
+    ) -> Result<HashMap<String, AnnotatedGLSL>> {
+        if options.case_insensitive_includes {
+            GLSLTree::fold_include_case(&mut src, &src_map);
+        }
+        let mismatch_allowed = options
+            .version_mismatch_allowed
+            .iter()
+            .any(|allowed| *allowed == path || *allowed == src.path);
+        if let (Some(root_version), Some((_, src_version))) = (version, src.version_pragma) {
+            let strict = options.version_policy == VersionPolicy::Strict;
+            if root_version != src_version && strict && !mismatch_allowed && !options.library_mode
+            {
+                return Err(Error::VersionMismatch {
+                    root_version,
+                    src_version,
+                    src_path: path.to_string(),
+                });
+            }
+        }
+
+        if src.includes.len() > options.max_includes_per_file {
+            return Err(Error::TooManyIncludes {
+                path: path.to_string(),
+                count: src.includes.len(),
+            });
+        }
+
+        let requires_version = options.require_version && options.version_handling;
+        if branch.is_empty() && requires_version && src.version_pragma.is_none() {
+            return Err(Error::MissingVersion {
+                path: path.to_string(),
+            });
+        }
+
+        if branch.is_empty() && options.strict_version {
+            if let (Some((line, _)), Some(code)) = (src.version_pragma, src.first_code_line()) {
+                if code < line {
+                    return Err(Error::MisplacedVersion { line });
+                }
+            }
+        }
+
+        let version = if branch.is_empty() {
+            // root shader; fall back to the default version if no version pragma.
+            src.version_pragma
+                .map(|(_, v)| v)
+                .or(Some(options.default_version))
+        } else {
+            version
+        };
+
+        let branch = branch.push_front(path.to_string());
+        let dead = options
+            .dead_ranges(&src.conditionals, version.unwrap_or(options.default_version));
+        // Includes are traced in line order, so failures are reported in source order.
+        let mut includes: Vec<(&usize, &String)> = src.includes.iter().collect();
+        includes.sort();
+        let include_files = includes
+            .into_iter()
+            .filter(|(i, _)| !dead.iter().any(|range| range.contains(i)))
+            .filter(|(_, included_file)| options.follows_include(included_file, path))
+            .filter(|(_, included_file)| {
+                !(options.allow_back_edges && branch.iter().any(|p| *included_file == p))
+            })
+            .filter_map(|(i, included_file)| {
+                let error = if branch.last() == Some(included_file) {
+                    Error::IncludesRoot {
+                        path: path.to_string(),
+                        line: *i,
+                    }
+                } else if branch.iter().any(|p| included_file == p) {
+                    let branch = branch.push_front(included_file.clone());
+                    // The cycle runs from the included file's earlier include to here.
+                    let mut cycle: Vec<String> = branch
+                        .iter()
+                        .skip(1)
+                        .take_while(|p| *p != included_file)
+                        .cloned()
+                        .collect();
+                    cycle.push(included_file.clone());
+                    cycle.reverse();
+                    cycle.push(included_file.clone());
+                    Error::Cycle { cycle, branch }
+                } else {
+                    return Some(Ok((included_file.clone(), src.optional_includes.contains(i))));
+                };
+                match options.error_sink {
+                    Some(ref errors) => {
+                        errors.lock().unwrap().push(error);
+                        None
+                    }
+                    None => Some(Err(error)),
+                }
+            })
+            .collect::<Result<Vec<(String, bool)>>>()?;
+        // A file reached again deeper down keeps the deepest nesting it was traced at.
+        let depth = src_map.get(path).map_or(0, |traced| traced.depth);
+        src.depth = depth.max(branch.len() - 1);
+        src_map.insert(path.to_string(), src);
+        include_files
+            .into_iter()
+            .try_fold(src_map, move |src_map, (included_file, optional)| {
+                GLSLTree::build_node(
+                    &included_file,
+                    optional,
+                    include_dirs,
+                    options,
+                    &branch,
+                    version,
+                    src_map,
+                )
+            })
+    }
+
+    /// Respells the includes of a file as the first spelling traced of the same path
+    /// ignoring case, so they share keys.
+    fn fold_include_case(src: &mut AnnotatedGLSL, src_map: &HashMap<String, AnnotatedGLSL>) {
+        // Includes of traced files which are still being traced count as traced.
+        let mut spellings: Vec<String> = src_map
+            .keys()
+            .chain(src_map.values().flat_map(|src| src.includes.values()))
+            .cloned()
+            .collect();
+        let mut lines: Vec<usize> = src.includes.keys().cloned().collect();
+        lines.sort();
+        for line in lines {
+            let include = src.includes.get_mut(&line).unwrap();
+            let folded = include.to_lowercase();
+            match spellings.iter().find(|spelling| spelling.to_lowercase() == folded) {
+                Some(spelling) => *include = spelling.clone(),
+                None => spellings.push(include.clone()),
+            }
+        }
+    }
+
+    fn render_body(
+        root_path: &str,
+        src_map: &HashMap<String, AnnotatedGLSL>,
+        options: &Options,
+    ) -> Body {
+        let mut extensions = Vec::new();
+        let mut files = vec![(root_path.to_string(), 0)];
+        // The root may be included again through a back edge.
+        let mut seen = HashSet::new();
+        seen.insert(root_path.to_string());
+        let lines = GLSLTree::render_node(
+            root_path,
+            &src_map[root_path],
+            src_map,
+            options,
+            &mut seen,
+            &mut files,
+            &mut extensions,
+        );
+        files[0].1 = lines.len() + extensions.len();
+        let (files, site_lines) = files.into_iter().unzip();
+        Body {
+            extensions: extensions.into_iter().map(|(_, line)| line).collect(),
+            lines,
+            files,
+            site_lines,
+        }
+    }
+
+    /// Checks that the rendered body holds every line of every emitted file which isn't a
+    /// directive, exactly once and in order, except the license headers it strips.
+    #[cfg(test)]
+    fn verify_line_preservation(&self) -> bool {
+        let body = self.body();
+        body.files.iter().all(|path| {
+            let src = &self.src_map[path];
+            let header = if self.options.strip_license_headers && *path != self.root_path {
+                src.leading_comment_lines()
+            } else {
+                0
+            };
+            let is_code = |i: &usize| !src.directive_offsets.contains_key(i);
+            let expected: Vec<usize> = (header..src.lines.len()).filter(is_code).collect();
+            let emitted: Vec<usize> = body
+                .lines
+                .iter()
+                .filter_map(|(line, origin)| match origin {
+                    Some((origin, i)) if origin == path && *line == src.lines[*i] => Some(*i),
+                    _ => None,
+                })
+                .filter(is_code)
+                .collect();
+            emitted == expected
+        })
+    }
+
+    /// Returns the version and profile of the rendered output, given the tree's root.
+    fn resolved_version(
+        root_path: &str,
+        src_map: &HashMap<String, AnnotatedGLSL>,
+        options: &Options,
+    ) -> (usize, Option<Profile>) {
+        let root = &src_map[root_path];
+        let (version, profile) = match root.version_pragma {
+            Some((_, version)) => match root.profile {
+                Some(profile) => (version, Some(profile)),
+                None if options.inherit_default_profile => (version, options.default_profile),
+                None => (version, None),
+            },
+            None => (options.default_version, options.default_profile),
+        };
+        match options.version_policy {
+            VersionPolicy::Highest => {
+                let highest = src_map.values().filter_map(|src| src.version_pragma);
+                (highest.map(|(_, v)| v).fold(version, usize::max), profile)
+            }
+            VersionPolicy::Strict | VersionPolicy::RootWins => (version, profile),
+        }
+    }
+
+    fn render_tree(
+        root_path: &str,
+        src_map: &HashMap<String, AnnotatedGLSL>,
+        options: &Options,
+        body: &Body,
+    ) -> Rendering {
+        let (rendered, origins): (Vec<String>, Vec<Option<Origin>>) =
+            GLSLTree::output_lines(root_path, src_map, options, body)
+                .map(|(line, origin)| (line.into_owned(), origin.cloned()))
+                .unzip();
+        let text = rendered.join(options.newline_style.as_str());
+        match options.render_transform {
+            Some(ref transform) => Rendering {
+                text: (transform.0)(text.clone()),
+                untransformed: Some(text),
+                origins,
+            },
+            None => Rendering {
+                text,
+                untransformed: None,
+                origins,
+            },
+        }
+    }
+
+    /// Returns the lines of the rendered output, before any render transform, with the file
+    /// and line each came from.
+    fn output_lines<'a>(
+        root_path: &str,
+        src_map: &HashMap<String, AnnotatedGLSL>,
+        options: &'a Options,
+        body: &'a Body,
+    ) -> impl Iterator<Item = (Cow<'a, str>, Option<&'a Origin>)> {
+        let (version, profile) = GLSLTree::resolved_version(root_path, src_map, options);
+        let defines = options.defines.iter().map(|(name, value)| {
+            if value.is_empty() {
+                format!("#define {}", name)
+            } else {
+                format!("#define {} {}", name, value)
+            }
+        });
+        let version_text = version.to_string();
+        Some(version)
+            .filter(|_| options.renders_version_line())
+            .into_iter()
+            .map(move |v| (Cow::Owned(version_directive(v, profile)), None))
+            .chain(
+                body.extensions
+                    .iter()
+                    .map(|(line, origin)| (Cow::Borrowed(line.as_str()), origin.as_ref())),
+            )
+            .chain(defines.map(|define| (Cow::Owned(define), None)))
+            .chain(
+                body.lines
+                    .iter()
+                    .map(|(line, origin)| (Cow::Borrowed(line.as_str()), origin.as_ref())),
+            )
+            .map(move |(line, origin)| match options.version_macro {
+                Some(ref token) => (replace_identifier(line, token, &version_text), origin),
+                None => (line, origin),
+            })
+    }
+
+    fn render_node(
+        path: &str,
+        src: &AnnotatedGLSL,
+        src_map: &HashMap<String, AnnotatedGLSL>,
+        options: &Options,
+        seen: &mut HashSet<String>,
+        files: &mut Vec<(String, usize)>,
+        extensions: &mut Vec<((String, String), RenderedLine)>,
+    ) -> Vec<RenderedLine> {
+        let root = files.first().map(|(root, _)| root.as_str()).unwrap_or(path);
+        let version = GLSLTree::resolved_version(root, src_map, options).0;
+        let dead = options.dead_ranges(&src.conditionals, version);
+        let header = if options.strip_license_headers && !files.is_empty() && files[0].0 != path {
+            src.leading_comment_lines()
+        } else {
+            0
+        };
+        let live = |i: &usize| !dead.iter().any(|range| range.contains(i));
+        // Extensions guarded by conditionals stay in place, as hoisting would drop the guard.
+        let guarded = conditional::guarded_ranges(&src.conditionals);
+        let hoists = |i: &usize| {
+            options.hoist_extensions && !guarded.iter().any(|range| range.contains(i))
+        };
+        let mut numbering: Option<LineNumbering> = None;
+        src.lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                if let Some(include) = src.includes.get(&i) {
+                    let skipped = !live(&i) || !options.follows_include(include, path);
+                    if skipped || seen.contains(include) {
+                        None
+                    } else if let Some(src) = src_map.get(include) {
+                        seen.insert(include.clone());
+                        files.push((include.clone(), 0));
+                        let site = files.len() - 1;
+                        let hoisted = extensions.len();
+                        let lines = GLSLTree::render_node(
+                            include, src, src_map, options, seen, files, extensions,
+                        );
+                        files[site].1 = lines.len() + extensions.len() - hoisted;
+                        if let Some(ref mut numbering) = numbering {
+                            numbering.emitted += lines.len();
+                        }
+                        Some(lines)
+                    } else {
+                        None
+                    }
+                } else if let Some(true) = src.version_pragma.map(|(j, _)| j == i) {
+                    None
+                } else if i < header {
+                    None
+                } else if let (true, Some(extension)) = (hoists(&i), src.extensions.get(&i)) {
+                    if !extensions.iter().any(|(e, _)| e == extension) {
+                        extensions.push((
+                            extension.clone(),
+                            (String::from(line.trim()), Some((path.to_string(), i))),
+                        ));
+                    }
+                    None
+                } else if let Some(&(number, source_string)) =
+                    src.line_directives.get(&i).filter(|_| live(&i))
+                {
+                    numbering = Some(LineNumbering {
+                        line: i,
+                        number,
+                        source_string,
+                        emitted: 0,
+                    });
+                    Some(vec![(line.clone(), Some((path.to_string(), i)))])
+                } else {
+                    let injected = options.injections.get(line.trim());
+                    let mut lines = Vec::new();
+                    if let Some(ref mut numbering) = numbering {
+                        // Restore the numbering the directive implies if flattening has moved
+                        // this line relative to it.
+                        if numbering.emitted != i - numbering.line - 1 {
+                            numbering.number += i - numbering.line - 1;
+                            numbering.line = i - 1;
+                            numbering.emitted = 0;
+                            lines.push((numbering.directive(), None));
+                        }
+                        numbering.emitted += injected.map_or(1, |text| text.lines().count());
+                    }
+                    match injected {
+                        Some(text) => lines.extend(text.lines().map(|injected| {
+                            (String::from(injected), Some((path.to_string(), i)))
+                        })),
+                        None => lines.push((line.clone(), Some((path.to_string(), i)))),
+                    }
+                    Some(lines)
+                }
+            })
+            .flatten()
+            .collect()
+    }
+}
+
+/// The numbering set by a `#line` directive, while rendering the file it is in.
+struct LineNumbering {
+    /// The line of the file the directive is on.
+    line: usize,
+    number: usize,
+    source_string: Option<usize>,
+    /// The number of lines rendered after the directive so far.
+    emitted: usize,
+}
+
+impl LineNumbering {
+    fn directive(&self) -> String {
+        match self.source_string {
+            Some(source_string) => format!("#line {} {}", self.number, source_string),
+            None => format!("#line {}", self.number),
+        }
+    }
+}
+
+/// Describes an error, given how to show each path it mentions.
+fn describe_error<F>(error: &Error, show: F, f: &mut fmt::Formatter) -> fmt::Result
+where
+    F: Fn(&Path) -> &Path,
+{
+    let show_str = |path: &str| show(Path::new(path)).display().to_string();
+    match error {
+        Error::Io(error) => write!(f, "IO Error: {}", error),
+        Error::FailedToOpen {
+            path,
+            searched_dirs,
+            cause,
+        } => write!(
+            f,
+            "Failed to open {} after searching {:?}: {}",
+            show_str(path),
+            searched_dirs.iter().map(|dir| show_str(dir)).collect::<Vec<String>>(),
+            cause
+        ),
+        Error::Cycle { cycle, branch } => {
+            f.write_str(&describe_cycle(cycle, branch, |path| show_str(path)))
+        }
+        Error::VersionMismatch {
+            root_version,
+            src_version,
+            src_path,
+        } => write!(
+            f,
+            "Versions {:?} and {:?} don't match. See {}",
+            root_version,
+            src_version,
+            show_str(src_path)
+        ),
+        Error::MissingRoot => f.write_str("Root file is missing"),
+        Error::MisplacedVersion { line } => write!(
+            f,
+            "The version pragma on line {} must come before any code",
+            line + 1
+        ),
+        Error::TooManyIncludes { path, count } => write!(
+            f,
+            "{} has {} includes, more than the configured limit",
+            show_str(path),
+            count
+        ),
+        Error::NonUnicodePath { path } => write!(f, "Path {:?} is not valid unicode", show(path)),
+        Error::MalformedInclude { path, line } => write!(
+            f,
+            "The include on line {} of {} must be a single quoted or bracketed path",
+            line + 1,
+            show_str(path)
+        ),
+        Error::InvalidIncludeDir { path } => write!(
+            f,
+            "Include directory {} does not exist or is not a directory",
+            show_str(path)
+        ),
+        Error::IncludeEscapesSandbox { path } => {
+            write!(f, "{} is outside the sandbox root", show_str(path))
+        }
+        Error::OutsideRequiredDir { path, dir } => write!(
+            f,
+            "{} resolves outside {}, which every file is required to be within",
+            show_str(path),
+            show_str(dir)
+        ),
+        Error::IncludesRoot { path, line } => write!(
+            f,
+            "{} includes the root on line {}; did you mean to include another file?",
+            show_str(path),
+            line + 1
+        ),
+        Error::MtimeUnsupported { path } => write!(
+            f,
+            "Modification times are unavailable for {}, so it can't be watched. \
+             Use a resolver which reports none to load it without watching",
+            show_str(path)
+        ),
+        Error::MissingVersion { path } => write!(f, "{} has no version pragma", show_str(path)),
+        Error::DuplicateVersion { path, lines } => write!(
+            f,
+            "{} has more than one version pragma, on lines {}",
+            show_str(path),
+            lines.iter().map(|line| (line + 1).to_string()).collect::<Vec<_>>().join(", ")
+        ),
+        Error::FileTooLarge { path, size } => write!(
+            f,
+            "{} is {} bytes, more than the configured limit",
+            show_str(path),
+            size
+        ),
+        Error::EmptyOutput { path } => {
+            write!(f, "The tree rooted at {} renders no code", show_str(path))
+        }
+        Error::BackslashInclude { path } => write!(
+            f,
+            "{} can't be found, and uses `\\` as a path separator, which only Windows \
+             recognizes; write `/` or enable `GLSLTreeBuilder::normalize_backslashes`",
+            show_str(path)
+        ),
+        Error::UndefinedEnvVar { name } => write!(
+            f,
+            "Environment variable {} is referenced in a path but not set",
+            name
+        ),
+    }
+}
+
+/// Describes a cycle, with the files leading to it, given how to show each path.
+fn describe_cycle<F>(cycle: &[String], branch: &List<String>, show: F) -> String
+where
+    F: Fn(&str) -> String,
+{
+    let show_all = |paths: Vec<&String>| {
+        paths.into_iter().map(|path| show(path)).collect::<Vec<String>>().join(" -> ")
+    };
+    let mut description = format!(
+        "There is a cycle through these imports: {}",
+        show_all(cycle.iter().collect())
+    );
+    let mut ancestors: Vec<&String> = branch.iter().skip(cycle.len()).collect();
+    if !ancestors.is_empty() {
+        ancestors.reverse();
+        description.push_str(&format!(", reached through {}", show_all(ancestors)));
+    }
+    description
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn escape_make_path(path: &str) -> String {
+    path.replace('$', "$$").replace(' ', "\\ ").replace('#', "\\#")
+}
+
+/// Replaces each occurrence of the identifier `name` in the text, leaving occurrences
+/// inside longer identifiers alone.
+fn replace_identifier<'a>(text: Cow<'a, str>, name: &str, with: &str) -> Cow<'a, str> {
+    let is_identifier = |c: char| c.is_alphanumeric() || c == '_';
+    let mut replaced = String::new();
+    let mut rest = 0;
+    for (at, _) in text.match_indices(name) {
+        let end = at + name.len();
+        if text[..at].chars().next_back().is_some_and(is_identifier)
+            || text[end..].chars().next().is_some_and(is_identifier)
+        {
+            continue;
+        }
+        replaced.push_str(&text[rest..at]);
+        replaced.push_str(with);
+        rest = end;
+    }
+    if rest == 0 {
+        return text;
+    }
+    replaced.push_str(&text[rest..]);
+    Cow::Owned(replaced)
+}
+
+/// Returns the version line rendered for the given version and profile.
+fn version_directive(version: usize, profile: Option<Profile>) -> String {
+    match profile {
+        Some(profile) => format!("#version {} {}", version, profile),
+        None => format!("#version {}", version),
+    }
+}
+
+/// Replaces a leading `~` component of the path with the given home directory. Paths are
+/// left as they are when there is no home directory.
+#[cfg(feature = "tilde")]
+fn expand_tilde(path: &Path, home: Option<PathBuf>) -> PathBuf {
+    match (path.strip_prefix("~"), home) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Replaces `$VAR` and `${VAR}` references in the path with the values the given function
+/// looks up for them. A `$` which doesn't start a reference is kept as it is.
+#[cfg(feature = "env")]
+fn expand_env_vars<F>(path: &str, var: F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        let after = &rest[(i + 1)..];
+        let (name, len) = match after.strip_prefix('{').and_then(|s| s.find('}')) {
+            Some(end) => (&after[1..(end + 1)], end + 2),
+            None => {
+                let len = after
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(after.len());
+                (&after[..len], len)
+            }
+        };
+        if name.is_empty() {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        match var(name) {
+            Some(value) => expanded.push_str(&value),
+            None => {
+                return Err(Error::UndefinedEnvVar {
+                    name: String::from(name),
+                })
+            }
+        }
+        rest = &after[len..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(feature = "env")]
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+impl fmt::Display for GLSLTree {
+    /// Writes the rendered source, as returned by `render`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.render())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    /// Returns an empty directory for a test to write files to.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("glslwatch-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("scratch dir");
+        dir
+    }
+
+    #[test]
+    fn it_works() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        println!("render: {}", tree.render());
+    }
+
+    #[test]
+    fn extensions_preserved_in_place() {
+        let tree = GLSLTree::new("src/test_glsl/extension.vert", &["src/test_glsl"])
+            .expect("extension tree");
+        let lines: Vec<&str> = tree.render().lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "#version 330",
+                "float a() {",
+                "    return 1.0;",
+                "}",
+                "#extension GL_OES_standard_derivatives : enable",
+                "float header() {",
+                "    return 2.0;",
+                "}",
+                "#extension GL_ARB_shading_language_420pack : require",
+                "",
+                "void main() {",
+                "}",
+            ]
+        );
+    }
+
+    #[test]
+    fn display_writes_render() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("tree");
+        assert_eq!(format!("{}", tree), tree.render());
+    }
+
+    #[test]
+    fn render_without_version_line() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("tree");
+        let body = tree.render_without_version();
+        assert!(!body.contains("#version"));
+        assert_eq!(format!("#version 150\n{}", body), tree.render());
+
+        // The version line goes whatever the newline style, and before any render transform.
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .newline_style(NewlineStyle::CrLf)
+            .render_transform(|s| format!("// hdr\n{}", s))
+            .build()
+            .expect("tree");
+        let body = tree.render_without_version();
+        assert!(body.starts_with("// hdr\n"));
+        assert!(!body.contains("#version"));
+        assert_eq!(
+            tree.render(),
+            format!("// hdr\n#version 150\r\n{}", &body["// hdr\n".len()..])
+        );
+    }
+
+    #[test]
+    fn content_hashes() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+
+        let tree = || GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("tree");
+        let mut variant = tree();
+        assert_eq!(tree().content_hash(), variant.content_hash());
+        assert_eq!(variant.content_hash(), fnv1a(variant.render().as_bytes()));
+        variant.set_defines(&[("FAST", "")]);
+        assert_ne!(tree().content_hash(), variant.content_hash());
+    }
+
+    #[test]
+    fn export_lists_sources() {
+        let tree = GLSLTree::new("src/test_glsl/extension.vert", &["src/test_glsl"])
+            .expect("tree");
+        let export = tree.export();
+        let mut lines = export.lines();
+        assert_eq!(lines.next(), Some("#version 330"));
+        assert_eq!(
+            lines.next(),
+            Some(
+                "// Generated from: src/test_glsl/extension.vert including \
+                 src/test_glsl/extension_a.vert, src/test_glsl/extension_header.vert, \
+                 src/test_glsl/extension_b.vert"
+            )
+        );
+        assert_eq!(lines.collect::<Vec<_>>(), tree.render().lines().skip(1).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn dropped_render_is_recomputed() {
+        let mut tree =
+            GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        let rendered = String::from(tree.render());
+        tree.drop_rendered();
+        assert!(tree.rendered.get().is_none());
+        assert_eq!(tree.render_uncached(), rendered);
+        assert!(tree.rendered.get().is_none());
+        assert_eq!(tree.render(), rendered);
+        assert!(tree.rendered.get().is_some());
+    }
+
+    #[test]
+    fn rendered_on_first_use() {
+        let tree =
+            GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        assert!(tree.body.get().is_none());
+        assert!(tree.rendered.get().is_none());
+        assert!(!tree.expired().expect("expiry"));
+        assert!(tree.rendered.get().is_none());
+        let rendered = String::from(tree.render());
+        assert!(tree.rendered.get().is_some());
+
+        let tree = tree.refresh().expect("refreshed tree");
+        assert!(tree.rendered.get().is_none());
+        assert_eq!(tree.render(), rendered);
+    }
+
+    #[test]
+    fn defines_injected_and_replaced() {
+        let mut tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .defines(&[("QUALITY", "2"), ("FAST", "")])
+            .build()
+            .expect("tree with defines");
+        let lines: Vec<String> = tree.render().lines().map(String::from).collect();
+        assert_eq!(&lines[..3], &["#version 150", "#define QUALITY 2", "#define FAST"]);
+
+        tree.set_defines(&[("QUALITY", "3")]);
+        assert!(tree.body.get().is_some());
+        let variant: Vec<String> = tree.render().lines().map(String::from).collect();
+        assert_eq!(&variant[..2], &["#version 150", "#define QUALITY 3"]);
+        assert_eq!(&variant[2..], &lines[3..]);
+    }
+
+    #[test]
+    fn conditional_includes_follow_live_branch() {
+        let build = |defines: &[(&str, &str)]| {
+            GLSLTree::builder("src/test_glsl/conditional.vert")
+                .include_dirs(&["src/test_glsl"])
+                .defines(defines)
+                .prune_conditionals(true)
+                .build()
+        };
+        let fancy = build(&[("FANCY", ""), ("QUALITY", "2")]).expect("fancy tree");
+        assert!(fancy.render().contains("float fancy()"));
+        assert!(!fancy.render().contains("someotherfunc"));
+
+        let plain = build(&[]).expect("plain tree");
+        assert!(!plain.render().contains("float fancy()"));
+        assert!(plain.render().contains("someotherfunc"));
+
+        // The else branch includes a file which doesn't exist.
+        build(&[("FANCY", ""), ("QUALITY", "1")]).expect_err("missing include in else branch");
+    }
+
+    #[test]
+    fn conditional_includes_traced_by_default() {
+        let dir = scratch_dir("conditional-default");
+        let root = dir.join("root.vert");
+        fs::write(dir.join("foo.glsl"), "float foo;\n").expect("foo");
+
+        // Macros the sources define and the compiler predefines aren't known to the tree, so
+        // without pruning every branch is traced.
+        fs::write(&root, "#define USE_FOO\n#ifdef USE_FOO\n#include \"foo.glsl\"\n#endif\n")
+            .expect("root");
+        let tree = GLSLTree::new(&root, &[&dir]).expect("tree");
+        assert!(tree.render().contains("float foo;"));
+        assert!(tree.file_info("foo.glsl").is_some());
+
+        fs::write(
+            &root,
+            "#version 330\n#if __VERSION__ >= 300\n#include \"foo.glsl\"\n#endif\n",
+        )
+        .expect("root");
+        let tree = GLSLTree::new(&root, &[&dir]).expect("tree");
+        assert!(tree.render().contains("float foo;"));
+        assert!(tree.file_info("foo.glsl").is_some());
+    }
+
+    #[test]
+    fn line_contributions_account_for_dedup() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("tree");
+        let contributions = tree.line_contributions();
+        assert_eq!(contributions.len(), 6);
+        assert_eq!(contributions[0], (String::from("src/test_glsl/simple.vert"), 7));
+        assert_eq!(contributions[1], (String::from("common.vert"), 2));
+        let counts: HashMap<String, usize> = contributions.into_iter().collect();
+        assert_eq!(
+            counts,
+            hashmap! {
+                String::from("src/test_glsl/simple.vert") => 7,
+                String::from("common.vert") => 2,
+                String::from("nested.vert") => 3,
+                String::from("diamond_a.vert") => 0,
+                String::from("diamond_b.vert") => 0,
+                String::from("diamond_c.vert") => 3,
+            }
+        );
+    }
+
+    #[test]
+    fn version_handling_disabled() {
+        let tree = GLSLTree::builder("src/test_glsl/main.wgsl")
+            .include_dirs(&["src/test_glsl"])
+            .version_handling(false)
+            .build()
+            .expect("wgsl tree");
+        assert_eq!(
+            tree.render(),
+            "fn color() -> vec4<f32> {\n    return vec4<f32>(1.0);\n}\n\
+             @fragment\n\
+             fn main() -> @location(0) vec4<f32> {\n    return color();\n}"
+        );
+    }
+
+    #[test]
+    fn library_mode() {
+        let dir = scratch_dir("library_mode");
+        let root = dir.join("lib.glsl");
+        fs::write(&root, "#include \"a.glsl\"\n#include \"b.glsl\"\n").expect("root");
+        fs::write(dir.join("a.glsl"), "#version 330\nfloat a;\n").expect("a");
+        fs::write(dir.join("b.glsl"), "#version 450\nfloat b;\n").expect("b");
+        let build = |library_mode| {
+            GLSLTree::builder(root.to_str().unwrap())
+                .library_mode(library_mode)
+                .build()
+        };
+        match build(false) {
+            Err(Error::VersionMismatch { .. }) => (),
+            other => panic!("expected version mismatch, got {:?}", other),
+        }
+        let tree = build(true).expect("library tree");
+        assert_eq!(tree.render(), "float a;\nfloat b;");
+        assert_eq!(tree.render_without_version(), tree.render());
+        assert_eq!(tree.version_line(), "");
+    }
+
+    #[test]
+    fn lines_preserved() {
+        let builder = |path| GLSLTree::builder(path).include_dirs(&["src/test_glsl"]);
+        let trees = vec![
+            builder("src/test_glsl/simple.vert").build(),
+            builder("src/test_glsl/deduped.vert").build(),
+            builder("src/test_glsl/extension.vert").hoist_extensions(true).build(),
+            builder("src/test_glsl/licensed.vert").strip_license_headers(true).build(),
+            builder("src/test_glsl/back_edge.vert").allow_back_edges(true).build(),
+            builder("src/test_glsl/conditional.vert")
+                .defines(&[("FANCY", ""), ("QUALITY", "2")])
+                .prune_conditionals(true)
+                .build(),
+        ];
+        for tree in trees {
+            let tree = tree.expect("tree");
+            assert!(tree.verify_line_preservation(), "lines of {} lost", tree.root_path);
+        }
+
+        // A body missing a line fails the check.
+        let tree = builder("src/test_glsl/simple.vert").build().expect("tree");
+        let mut body = GLSLTree::render_body(&tree.root_path, &tree.src_map, &tree.options);
+        body.lines.pop();
+        let _ = tree.body.set(body);
+        assert!(!tree.verify_line_preservation());
+    }
+
+    #[test]
+    fn newest_candidate_preferred() {
+        let dir = scratch_dir("newest_candidate");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#include \"lib.vert\"\n").expect("root");
+        let layers = [dir.join("base"), dir.join("override")];
+        let now = std::time::SystemTime::now();
+        let set_mtime = |layer: &PathBuf, secs_ago| {
+            let file = fs::File::options().write(true).open(layer.join("lib.vert")).expect("lib");
+            file.set_modified(now - std::time::Duration::from_secs(secs_ago)).expect("mtime");
+        };
+        for layer in &layers {
+            fs::create_dir_all(layer).expect("layer");
+            let name = layer.file_name().unwrap().to_str().unwrap();
+            fs::write(layer.join("lib.vert"), format!("float {};\n", name)).expect("lib");
+        }
+        let render = |policy| {
+            let tree = GLSLTree::builder(root.to_str().unwrap())
+                .include_dirs(&layers)
+                .ambiguity_policy(policy)
+                .build()
+                .expect("layered tree");
+            String::from(tree.render())
+        };
+
+        set_mtime(&layers[0], 100);
+        set_mtime(&layers[1], 10);
+        assert_eq!(render(AmbiguityPolicy::FirstFound), "#version 110\nfloat base;");
+        assert_eq!(render(AmbiguityPolicy::Newest), "#version 110\nfloat override;");
+
+        set_mtime(&layers[0], 1);
+        assert_eq!(render(AmbiguityPolicy::Newest), "#version 110\nfloat base;");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_export() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        let json = tree.to_json();
+        let value: serde_json::Value = serde_json::from_str(&json).expect("valid json");
+        assert_eq!(value.to_string(), json);
+        assert_eq!(value["root"], "src/test_glsl/simple.vert");
+        assert_eq!(value["version"], 150);
+        assert_eq!(value["profile"], serde_json::Value::Null);
+        let files = value["files"].as_array().expect("files");
+        assert_eq!(files.len(), 6);
+        assert_eq!(
+            files[0],
+            json!({
+                "key": "src/test_glsl/simple.vert",
+                "path": "src/test_glsl/simple.vert",
+                "version": 150,
+                "line_count": 9,
+                "includes": [{ "line": 1, "path": "common.vert" }],
+            })
+        );
+        assert_eq!(files[1]["key"], "common.vert");
+        assert_eq!(files[1]["path"], "src/test_glsl/common.vert");
+        assert_eq!(files[1]["includes"].as_array().map(Vec::len), Some(3));
+    }
+
+    #[test]
+    fn injections_replace_markers() {
+        let dir = scratch_dir("injections");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#version 330\n  // @INJECT uniforms\nvoid main() {}\n").expect("root");
+        let uniforms = "uniform float a;\nuniform float b;";
+        let injections = hashmap!{
+            String::from("// @INJECT uniforms") => String::from(uniforms),
+            String::from("// @INJECT constants") => String::from("const float c = 1.0;"),
+        };
+        let tree = GLSLTree::builder(root.to_str().unwrap())
+            .with_injections(injections)
+            .build()
+            .expect("tree with injections");
+        assert_eq!(
+            tree.render(),
+            "#version 330\nuniform float a;\nuniform float b;\nvoid main() {}"
+        );
+    }
+
+    #[test]
+    fn mixed_indentation_warned() {
+        let dir = scratch_dir("mixed_indentation");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#include \"tabs.vert\"\n#include \"mixed.vert\"\n").expect("root");
+        fs::write(dir.join("tabs.vert"), "void a() {\n\tfloat a;\n\n\t\tfloat b;\n}\n")
+            .expect("tabs");
+        fs::write(dir.join("mixed.vert"), "void b() {\n    float a;\n\tfloat b;\n}\n")
+            .expect("mixed");
+        let build = |warn_mixed_indentation| {
+            GLSLTree::builder(root.to_str().unwrap())
+                .warn_mixed_indentation(warn_mixed_indentation)
+                .build()
+                .expect("tree")
+        };
+        assert!(build(false).warnings().is_empty());
+        let warnings = build(true).warnings();
+        assert_eq!(
+            warnings,
+            vec![Warning::MixedIndentation {
+                path: String::from("mixed.vert"),
+                line: 2,
+            }]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "mixed.vert mixes tabs and spaces in its indentation on line 3"
+        );
+
+        fs::write(dir.join("mixed.vert"), "void b() {\n\t  float a;\n}\n").expect("mixed");
+        assert_eq!(
+            build(true).warnings(),
+            vec![Warning::MixedIndentation {
+                path: String::from("mixed.vert"),
+                line: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn oversized_files_rejected() {
+        let dir = scratch_dir("oversized_files");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#include \"generated.vert\"\n").expect("root");
+        fs::write(dir.join("generated.vert"), "float a;\n".repeat(16)).expect("generated");
+        let build = |max_file_size| {
+            GLSLTree::builder(root.to_str().unwrap())
+                .max_file_size(max_file_size)
+                .build()
+        };
+        assert!(build(160).is_ok());
+        match build(64) {
+            Err(Error::FileTooLarge { path, size }) => {
+                assert!(path.ends_with("generated.vert"));
+                assert_eq!(size, 144);
+            }
+            other => panic!("expected oversized file, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn includes_resolved_without_loading() {
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .include_overrides(hashmap!{
+                String::from("pinned.vert") => String::from("src/test_glsl/alt/nested.vert"),
+                String::from("gone.vert") => String::from("src/test_glsl/gone.vert"),
+            })
+            .resolve_include(|include, includer| match (include, includer) {
+                ("hooked.vert", "common.vert") => Some(String::from("src/test_glsl/fancy.vert")),
+                _ => None,
+            })
+            .build()
+            .expect("my tree");
+        let resolve = |include, from| tree.resolve(include, from);
+        assert_eq!(resolve("nested.vert", None).as_deref(), Some("src/test_glsl/nested.vert"));
+        assert_eq!(resolve("./fancy.vert", None).as_deref(), Some("src/test_glsl/fancy.vert"));
+        assert_eq!(
+            resolve("pinned.vert", None).as_deref(),
+            Some("src/test_glsl/alt/nested.vert")
+        );
+        assert_eq!(
+            resolve("hooked.vert", Some("common.vert")).as_deref(),
+            Some("src/test_glsl/fancy.vert")
+        );
+        assert_eq!(resolve("hooked.vert", None), None);
+        assert_eq!(resolve("gone.vert", None), None);
+        assert_eq!(resolve("missing.vert", None), None);
+    }
+
+    #[test]
+    fn required_extensions_collected() {
+        let tree = GLSLTree::new("src/test_glsl/extension.vert", &["src/test_glsl"])
+            .expect("extension tree");
+        assert_eq!(
+            tree.required_extensions(),
+            vec![
+                (String::from("GL_OES_standard_derivatives"), String::from("enable")),
+                (String::from("GL_ARB_shading_language_420pack"), String::from("require")),
+            ]
+        );
+
+        let dir = scratch_dir("required_extensions");
+        let root = dir.join("root.vert");
+        let root_src = "#include \"a.vert\"\n#include \"b.vert\"\n#extension GL_W : enable\n";
+        fs::write(&root, root_src).expect("root");
+        fs::write(dir.join("a.vert"), "#extension GL_X : enable\n#extension GL_Y : require\n")
+            .expect("a");
+        fs::write(dir.join("b.vert"), "#extension GL_X : require\n#extension GL_Z : warn\n")
+            .expect("b");
+        let tree = GLSLTree::new(&root, &[] as &[&str]).expect("tree");
+        let required: Vec<(&str, &str)> = vec![
+            ("GL_X", "enable"),
+            ("GL_Y", "require"),
+            ("GL_Z", "warn"),
+            ("GL_W", "enable"),
+        ];
+        assert_eq!(
+            tree.required_extensions(),
+            required
+                .into_iter()
+                .map(|(name, behavior)| (String::from(name), String::from(behavior)))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reads_overridden() {
+        let dir = scratch_dir("read_override");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#include \"generated.vert\"\n#include \"real.vert\"\n").expect("root");
+        fs::write(dir.join("real.vert"), "float real;\n").expect("real");
+        let generated = dir.join("generated.vert");
+        let tree = GLSLTree::builder(root.to_str().unwrap())
+            .include_dirs(&[&dir])
+            .read_override(move |path| {
+                if Path::new(path) == generated {
+                    Some(Ok(String::from("float generated;")))
+                } else {
+                    None
+                }
+            })
+            .build()
+            .expect("tree with generated include");
+        assert_eq!(tree.render(), "#version 110\nfloat generated;\nfloat real;");
+        assert!(!tree.expired().expect("expiry"));
+        assert_eq!(tree.file_info("generated.vert").and_then(|info| info.mtime), None);
+        assert!(tree.file_info("real.vert").and_then(|info| info.mtime).is_some());
+    }
+
+    #[test]
+    fn constructed_with_warnings() {
+        let (tree, warnings) =
+            GLSLTree::new_with_warnings("src/test_glsl/simple.vert", &["src/test_glsl"])
+                .expect("my tree");
+        assert_eq!(warnings, tree.warnings());
+        assert_eq!(
+            warnings,
+            vec![Warning::UnreachedInclude {
+                path: String::from("diamond_b.vert"),
+            }]
+        );
+
+        let dir = scratch_dir("constructed_with_warnings");
+        let root = dir.join("root.vert");
+        fs::write(&root, "void main() {\n    float a;\n\tfloat b;\n}\n").expect("root");
+        let (tree, warnings) = GLSLTree::builder(root.to_str().unwrap())
+            .warn_mixed_indentation(true)
+            .build_with_warnings()
+            .expect("tree");
+        assert_eq!(warnings, tree.warnings());
+        assert_eq!(warnings.len(), 1);
+        assert!(GLSLTree::new_with_warnings(&root, &[] as &[&str]).expect("tree").1.is_empty());
+    }
+
+    #[test]
+    fn render_transformed() {
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .render_transform(|rendered| rendered + "\n// generated")
+            .build()
+            .expect("my tree");
+        let plain =
+            GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        assert_eq!(tree.render(), format!("{}\n// generated", plain.render()));
+        assert!(tree.render().starts_with("#version 150\n"));
+        assert_eq!(tree.render_chunks().concat(), tree.render());
+
+        // Origins refer to the lines before the transform, wherever it moves them.
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .render_transform(|rendered| format!("// hdr\n{}", rendered))
+            .build()
+            .expect("my tree");
+        assert_eq!(tree.render_chunks().concat(), tree.render());
+        assert_eq!(tree.origin(1), plain.origin(1));
+        assert!(tree.rendered_lines_with_origin().eq(plain.rendered_lines_with_origin()));
+    }
+
+    #[test]
+    fn module_roots_resolve_includes() {
+        let dir = scratch_dir("module_roots");
+        let write = |path: &str, src: &str| {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).expect("module dir");
+            fs::write(path, src).expect("source");
+        };
+        write("root.vert", "#include \"common/math.glsl\"\n#include \"common/color.glsl\"\n");
+        write("modules/common/math.glsl", "float outer_math;\n");
+        write("modules/common/color.glsl", "float outer_color;\n");
+        write("modules/render/common/math.glsl", "float inner_math;\n");
+        write("include/common/math.glsl", "float search_math;\n");
+        let modules = dir.join("modules");
+        let tree = GLSLTree::builder(dir.join("root.vert").to_str().unwrap())
+            .include_dirs(&[dir.join("include")])
+            .module_roots(&[modules.clone(), modules.join("render")])
+            .build()
+            .expect("tree with module roots");
+        assert_eq!(tree.render(), "#version 110\nfloat inner_math;\nfloat outer_color;");
+        assert_eq!(
+            tree.resolve("common/math.glsl", None).map(PathBuf::from),
+            Some(modules.join("render/common/math.glsl"))
+        );
+
+        let tree = GLSLTree::builder(dir.join("root.vert").to_str().unwrap())
+            .include_dirs(&[dir.join("include"), modules])
+            .build()
+            .expect("tree without module roots");
+        assert_eq!(tree.render(), "#version 110\nfloat search_math;\nfloat outer_color;");
+    }
+
+    #[test]
+    fn include_dirs_replaced_before_refresh() {
+        let dir = scratch_dir("set-include-dirs");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#version 150\n").expect("root");
+        let mut tree = GLSLTree::new(&root, &[] as &[&str]).expect("tree");
+
+        fs::write(&root, "#version 150\n#include \"nested.vert\"\n").expect("root");
+        tree.clone().refresh().expect_err("unresolvable include");
+        tree.set_include_dirs(vec![String::from("src/test_glsl")]);
+        let tree = tree.refresh().expect("refreshed tree");
+        assert!(tree.render().contains("someotherfunc"));
+    }
+
+    #[test]
+    fn version_refreshed_alone() {
+        let dir = scratch_dir("refresh_version");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#version 330\n#include \"a.vert\"\nfloat r;\n").expect("root");
+        fs::write(dir.join("a.vert"), "float a;\n").expect("a");
+        let mut tree = GLSLTree::new(&root, &[&dir]).expect("tree");
+        assert_eq!(tree.render(), "#version 330\nfloat a;\nfloat r;");
+
+        // Without its include on disk, the tree couldn't be re-traced.
+        fs::remove_file(dir.join("a.vert")).expect("remove a");
+        fs::write(&root, "#version 450 core\n#include \"a.vert\"\nfloat r;\n").expect("root");
+        tree.refresh_version().expect("version refreshed");
+        assert_eq!(tree.render(), "#version 450 core\nfloat a;\nfloat r;");
+        assert_eq!(tree.render_uncached(), tree.render());
+        assert_eq!(tree.version(), 450);
+
+        fs::write(&root, "#version 450\n#include \"b.vert\"\nfloat r;\n").expect("root");
+        fs::write(dir.join("b.vert"), "float b;\n").expect("b");
+        tree.refresh_version().expect("full refresh");
+        assert_eq!(tree.render(), "#version 450\nfloat b;\nfloat r;");
+
+        fs::write(&root, "#version 460\n#include \"a.vert\"\nfloat r;\n").expect("root");
+        assert!(tree.refresh_version().is_err());
+        assert_eq!(tree.render(), "#version 450\nfloat b;\nfloat r;");
+    }
+
+    #[test]
+    fn version_line_accessed() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        assert_eq!(tree.version_line(), "#version 150");
+        assert_eq!(tree.version_line(), tree.render().lines().next().unwrap());
+
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .default_profile(Profile::Core)
+            .inherit_default_profile(true)
+            .newline_style(NewlineStyle::CrLf)
+            .build()
+            .expect("my tree");
+        assert_eq!(tree.version_line(), "#version 150 core");
+
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .version_handling(false)
+            .build()
+            .expect("my tree");
+        assert_eq!(tree.version_line(), "");
+    }
+
+    #[test]
+    fn render_into_reuses_buffer() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        let mut buf = String::from("stale contents");
+        tree.render_into(&mut buf);
+        assert_eq!(buf, tree.render());
+        tree.render_into(&mut buf);
+        assert_eq!(buf, tree.render());
+    }
+
+    #[test]
+    fn render_to_matches_render() {
+        let render_to = |tree: &GLSLTree| {
+            let mut out = Vec::new();
+            tree.render_to(&mut out).expect("render to sink");
+            String::from_utf8(out).expect("utf-8 output")
+        };
+        let mut tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .newline_style(NewlineStyle::CrLf)
+            .defines(&[("FANCY", "1")])
+            .build()
+            .expect("my tree");
+        let streamed = render_to(&tree);
+        assert!(tree.rendered.get().is_none());
+        assert_eq!(streamed, tree.render());
+        assert_eq!(render_to(&tree), tree.render());
+        tree.drop_rendered();
+        assert_eq!(render_to(&tree), streamed);
+
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .render_transform(|rendered| rendered.to_uppercase())
+            .build()
+            .expect("transformed tree");
+        assert_eq!(render_to(&tree), tree.render());
+    }
+
+    #[test]
+    fn canonical_render_ignores_line_endings() {
+        let render = |name: &str, newline: &str| {
+            let dir = scratch_dir(name);
+            let root = dir.join("root.vert");
+            let src = ["#version 150", "#include \"header.vert\"", "void main() {}  ", "", ""];
+            fs::write(&root, src.join(newline)).expect("root");
+            fs::write(dir.join("header.vert"), ["float header();\t", ""].join(newline))
+                .expect("header");
+            GLSLTree::new(&root, &[&dir]).expect("tree").render_canonical()
+        };
+        let canonical = render("canonical-lf", "\n");
+        assert_eq!(canonical, "#version 150\nfloat header();\nvoid main() {}\n");
+        assert_eq!(render("canonical-crlf", "\r\n"), canonical);
+    }
+
+    #[test]
+    fn includes_confined_to_sandbox() {
+        let dir = scratch_dir("sandbox");
+        let sandbox = dir.join("sandbox");
+        fs::create_dir(&sandbox).expect("sandbox");
+        let root = sandbox.join("root.vert");
+        fs::write(sandbox.join("inside.vert"), "float inside();\n").expect("inside");
+        fs::write(dir.join("outside.vert"), "float outside();\n").expect("outside");
+        let build = || {
+            GLSLTree::builder(&root)
+                .include_dirs(&[&sandbox])
+                .sandbox_root(&sandbox)
+                .build()
+        };
+
+        fs::write(&root, "#include \"inside.vert\"\n").expect("root");
+        assert!(build().expect("confined tree").render().contains("inside"));
+
+        fs::write(&root, "#include \"../sandbox/inside.vert\"\n").expect("root");
+        assert!(build().is_ok());
+
+        fs::write(&root, "#include \"../outside.vert\"\n").expect("root");
+        match build() {
+            Err(Error::IncludeEscapesSandbox { path }) => assert!(path.ends_with("outside.vert")),
+            other => panic!("expected sandbox escape, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn files_required_within_dir() {
+        let dir = scratch_dir("require_within");
+        let tree_dir = dir.join("tree");
+        fs::create_dir(&tree_dir).expect("tree dir");
+        let root = tree_dir.join("root.vert");
+        fs::write(tree_dir.join("inside.vert"), "float inside();\n").expect("inside");
+        fs::write(dir.join("outside.vert"), "float outside();\n").expect("outside");
+        let build = |root: &Path| {
+            GLSLTree::builder(root)
+                .include_dirs(&[&tree_dir])
+                .require_within(&tree_dir)
+                .build()
+        };
+
+        fs::write(&root, "#include \"inside.vert\"\n").expect("root");
+        assert!(build(&root).is_ok());
+
+        fs::write(&root, "#include \"inside.vert\"\n#include \"../outside.vert\"\n")
+            .expect("root");
+        match build(&root) {
+            Err(Error::OutsideRequiredDir { path, .. }) => {
+                assert!(path.ends_with("outside.vert"))
+            }
+            other => panic!("expected file outside the required dir, got {:?}", other),
+        }
+
+        let outside_root = dir.join("outside_root.vert");
+        fs::write(&outside_root, "#include \"inside.vert\"\n").expect("outside root");
+        match build(&outside_root) {
+            Err(Error::OutsideRequiredDir { path, .. }) => {
+                assert!(path.ends_with("outside_root.vert"))
+            }
+            other => panic!("expected root outside the required dir, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn include_resolutions() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        let shared = tree.resolved_path_of("diamond_a.vert", 0);
+        assert_eq!(shared, Some("src/test_glsl/diamond_c.vert"));
+        assert_eq!(tree.resolved_path_of("diamond_b.vert", 0), shared);
+        assert_eq!(
+            tree.resolved_path_of("src/test_glsl/simple.vert", 1),
+            Some("src/test_glsl/common.vert")
+        );
+        assert_eq!(tree.resolved_path_of("src/test_glsl/simple.vert", 0), None);
+        assert_eq!(tree.resolved_path_of("missing.vert", 0), None);
+    }
+
+    #[test]
+    fn empty_output_rejected() {
+        let dir = scratch_dir("empty_output");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#version 330\n#include \"empty.vert\"\n\n").expect("root");
+        fs::write(dir.join("empty.vert"), "#version 330\n   \n").expect("empty header");
+        let build = |error_on_empty_output| {
+            GLSLTree::builder(root.to_str().unwrap())
+                .error_on_empty_output(error_on_empty_output)
+                .build()
+        };
+        assert!(build(false).is_ok());
+        match build(true) {
+            Err(Error::EmptyOutput { path }) => assert_eq!(path, root.to_str().unwrap()),
+            other => panic!("expected empty output, got {:?}", other),
+        }
+
+        fs::write(dir.join("empty.vert"), "float a;\n").expect("header");
+        assert!(build(true).is_ok());
+    }
+
+    #[cfg(feature = "tilde")]
+    #[test]
+    fn tilde_expanded() {
+        let home = PathBuf::from("/home/shader-author");
+        let expand = |path: &str| expand_tilde(Path::new(path), Some(home.clone()));
+        assert_eq!(expand("~/shaders/include"), home.join("shaders/include"));
+        assert_eq!(expand("~"), home.join(""));
+        assert_eq!(expand("shaders/~/include"), PathBuf::from("shaders/~/include"));
+        assert_eq!(expand("~other/shaders"), PathBuf::from("~other/shaders"));
+        assert_eq!(expand_tilde(Path::new("~/a"), None), PathBuf::from("~/a"));
+    }
+
+    #[cfg(feature = "env")]
+    #[test]
+    fn env_vars_expanded() {
+        let var = |name: &str| match name {
+            "SHADER_ROOT" => Some(String::from("/opt/shaders")),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let expand = |path: &str| expand_env_vars(path, var);
+        assert_eq!(expand("$SHADER_ROOT/lib.glsl").unwrap(), "/opt/shaders/lib.glsl");
+        assert_eq!(expand("${SHADER_ROOT}_v2/lib.glsl").unwrap(), "/opt/shaders_v2/lib.glsl");
+        assert_eq!(expand("a$EMPTY/b").unwrap(), "a/b");
+        assert_eq!(expand("cost$/$.glsl").unwrap(), "cost$/$.glsl");
+        assert_eq!(expand("${SHADER_ROOT").unwrap(), "${SHADER_ROOT");
+        match expand("$UNSET_ROOT/lib.glsl") {
+            Err(Error::UndefinedEnvVar { name }) => assert_eq!(name, "UNSET_ROOT"),
+            other => panic!("expected undefined variable, got {:?}", other),
+        }
+
+        let dir = scratch_dir("env_vars");
+        fs::create_dir(dir.join("lib")).expect("lib dir");
+        fs::write(dir.join("lib/colors.glsl"), "float color;\n").expect("colors");
+        fs::write(dir.join("root.vert"), "#include \"${GLSLWATCH_TEST_LIB}/colors.glsl\"\n")
+            .expect("root");
+        std::env::set_var("GLSLWATCH_TEST_ROOT", &dir);
+        std::env::set_var("GLSLWATCH_TEST_LIB", "lib");
+        let tree = GLSLTree::new(dir.join("root.vert"), &["$GLSLWATCH_TEST_ROOT"]).expect("tree");
+        assert_eq!(tree.render(), "#version 110\nfloat color;");
+
+        fs::write(dir.join("root.vert"), "#include \"$GLSLWATCH_TEST_UNSET/colors.glsl\"\n")
+            .expect("root");
+        match GLSLTree::new(dir.join("root.vert"), &[&dir]) {
+            Err(Error::UndefinedEnvVar { name }) => assert_eq!(name, "GLSLWATCH_TEST_UNSET"),
+            other => panic!("expected undefined variable, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chunks_concatenate_to_render() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        let chunks = tree.render_chunks();
+        assert_eq!(chunks.concat(), tree.render());
+        assert_eq!(chunks[0], "#version 150\n");
+        assert_eq!(
+            chunks.last().unwrap(),
+            "\nin vec2 vpos;\nout vec4 vcol;\n\nvoid main() {\n    \
+             vcol = vec4(0.0, 0.0, 0.0, 0.0);\n}"
+        );
+        assert!(chunks.iter().all(|chunk| !chunk.is_empty()));
+
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .newline_style(NewlineStyle::CrLf)
+            .build()
+            .expect("my tree");
+        assert_eq!(tree.render_chunks().concat(), tree.render());
+
+        // Adjacent synthesized lines share a chunk.
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .defines(&[("X", "")])
+            .build()
+            .expect("my tree");
+        let chunks = tree.render_chunks();
+        assert_eq!(chunks[0], "#version 150\n#define X\n");
+        assert_eq!(chunks.concat(), tree.render());
+    }
+
+    #[test]
+    fn version_macro_substituted() {
+        let dir = scratch_dir("version_macro");
+        let root = dir.join("root.vert");
+        let root_src = "#version 330\n#include \"lib.vert\"\nint v = __GLSL_VERSION__;\n";
+        fs::write(&root, root_src).expect("root");
+        fs::write(
+            dir.join("lib.vert"),
+            "#if __GLSL_VERSION__ >= 330\n#include \"new.vert\"\n#endif\n\
+             float a = __GLSL_VERSION__.0 + __GLSL_VERSION__;\n",
+        )
+        .expect("lib");
+        fs::write(dir.join("new.vert"), "float b;\n").expect("new");
+        let build = |version_macro: Option<&str>| {
+            GLSLTree::builder(root.to_str().unwrap())
+                .version_macro(version_macro.map(String::from))
+                .build()
+                .expect("tree")
+        };
+        assert_eq!(
+            build(Some("__GLSL_VERSION__")).render(),
+            "#version 330\n#if 330 >= 330\nfloat b;\n#endif\nfloat a = 330.0 + 330;\nint v = 330;"
+        );
+        assert!(build(None).render().contains("int v = __GLSL_VERSION__;"));
+
+        // Only the whole identifier is substituted.
+        fs::write(&root, "#version 330\nint MY_VERSION_X = VERSION + VERSION_2;\n").expect("root");
+        assert_eq!(
+            build(Some("VERSION")).render(),
+            "#version 330\nint MY_VERSION_X = 330 + VERSION_2;"
+        );
+        fs::write(&root, root_src).expect("root");
+        let tree = build(Some("__GLSL_VERSION__"));
+        let mut streamed = Vec::new();
+        tree.render_to(&mut streamed).expect("render to sink");
+        assert_eq!(streamed, tree.render().as_bytes());
+    }
+
+    #[test]
+    fn optional_includes() {
+        let tree = GLSLTree::new("src/test_glsl/optional.vert", &["src/test_glsl"])
+            .expect("tree with optional includes");
+        assert_eq!(
+            tree.render(),
+            "#version 150\nfloat someotherfunc() {\n    return 1.0;\n}\n\nvoid main() {\n}"
+        );
+        assert!(tree.file_info("platform.vert").is_none());
+        assert!(tree.file_info("nested.vert").is_some());
+    }
+
+    #[test]
+    fn includes_remapped() {
+        let mut tree =
+            GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        tree.remap_include("nested.vert", "alt/nested.vert").expect("remapped tree");
+        assert!(tree.render().contains("return 2.0;"));
+        assert!(!tree.render().contains("return 1.0;"));
+        let tree = tree.refresh().expect("refreshed tree");
+        assert!(tree.render().contains("return 2.0;"));
+
+        let mut tree =
+            GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        let rendered = String::from(tree.render());
+        tree.remap_include("nested.vert", "missing.vert").expect_err("unresolvable target");
+        tree.remap_include("nested.vert", "common.vert").expect_err("cycle");
+        assert_eq!(tree.render(), rendered);
+    }
+
+    #[test]
+    fn depfile_lists_dependencies() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        assert_eq!(
+            tree.write_depfile("out/simple.spv"),
+            "out/simple.spv: src/test_glsl/simple.vert src/test_glsl/common.vert \
+             src/test_glsl/diamond_a.vert src/test_glsl/diamond_b.vert \
+             src/test_glsl/diamond_c.vert src/test_glsl/nested.vert\n"
+        );
+
+        let dir = scratch_dir("depfile");
+        let root = dir.join("my shader#1.vert");
+        fs::write(&root, "void main() {}\n").expect("root");
+        let tree = GLSLTree::new(&root, &[] as &[&str]).expect("tree");
+        let escaped = root.to_str().unwrap().replace(' ', "\\ ").replace('#', "\\#");
+        assert_eq!(tree.write_depfile("a $b"), format!("a\\ $$b: {}\n", escaped));
+    }
+
+    #[test]
+    fn rerooted_within_tree() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        let common = tree.reroot("common.vert").expect("rerooted at key");
+        assert_eq!(common.root_path, "src/test_glsl/common.vert");
+        assert_eq!(common.src_map.len(), 5);
+        assert!(common.render().starts_with("#version 110\nfloat someotherfunc()"));
+        let by_path = tree.reroot("src/test_glsl/common.vert").expect("rerooted at path");
+        assert_eq!(by_path.render(), common.render());
+        let refreshed = common.clone().refresh().expect("refreshed rerooted tree");
+        assert_eq!(refreshed.render(), common.render());
+
+        let dir = scratch_dir("reroot");
+        let other = dir.join("other.vert");
+        fs::write(&other, "#version 330\n#include \"diamond_b.vert\"\n").expect("other");
+        fs::write(dir.join("diamond_c.vert"), "float changed();\n").expect("changed");
+        let other = tree.reroot(other.to_str().unwrap()).expect("rerooted at new file");
+        // The cached diamond_c.vert is reused rather than the one next to the new root.
+        assert!(other.render().starts_with("#version 330\nfloat duplicate_symbol"));
+        assert!(other.options.trace_cache.is_none());
+
+        assert!(tree.reroot("unknown.vert").is_none());
+    }
+
+    #[test]
+    fn errors_collected() {
+        let dir = scratch_dir("validate-collect");
+        let root = dir.join("root.vert");
+        fs::write(
+            &root,
+            "#version 150\n\
+             #include \"missing_a.vert\"\n\
+             #include \"cyclic.vert\"\n\
+             #include \"header.vert\"\n",
+        )
+        .expect("root");
+        fs::write(dir.join("cyclic.vert"), "#include \"cyclic.vert\"\n").expect("cyclic");
+        fs::write(dir.join("header.vert"), "#include \"missing_b.vert\"\n").expect("header");
+
+        let mut errors: Vec<String> = GLSLTree::validate_collect(&root, &[&dir])
+            .expect("validated root")
+            .iter()
+            .map(|error| match error {
+                Error::FailedToOpen { path, .. } => path.clone(),
+                Error::Cycle { .. } => String::from("cycle"),
+                other => panic!("unexpected error {:?}", other),
+            })
+            .collect();
+        errors.sort();
+        assert_eq!(errors, vec!["cycle", "missing_a.vert", "missing_b.vert"]);
+
+        GLSLTree::new(&root, &[&dir]).expect_err("fail fast");
+        GLSLTree::validate_collect(dir.join("missing.vert"), &[&dir]).expect_err("no root");
+        let simple = GLSLTree::validate_collect("src/test_glsl/simple.vert", &["src/test_glsl"]);
+        assert!(simple.expect("validated tree").is_empty());
+    }
+
+    #[test]
+    fn minimal_cycles_reported() {
+        let dir = scratch_dir("minimal_cycle");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#include \"a.vert\"\n").expect("root");
+        fs::write(dir.join("a.vert"), "#include \"b.vert\"\n").expect("a");
+        fs::write(dir.join("b.vert"), "#include \"c.vert\"\n").expect("b");
+        fs::write(dir.join("c.vert"), "#include \"d.vert\"\n").expect("c");
+        fs::write(dir.join("d.vert"), "#include \"c.vert\"\n").expect("d");
+        let error = GLSLTree::new(&root, &[&dir]).expect_err("cycle");
+        let root = root.to_str().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "There is a cycle through these imports: c.vert -> d.vert -> c.vert, \
+                 reached through {} -> a.vert -> b.vert",
+                root
+            )
+        );
+        match error {
+            Error::Cycle { cycle, branch } => {
+                assert_eq!(cycle, vec!["c.vert", "d.vert", "c.vert"]);
+                assert_eq!(
+                    branch.iter().map(String::as_str).collect::<Vec<&str>>(),
+                    vec!["c.vert", "d.vert", "c.vert", "b.vert", "a.vert", root]
+                );
+            }
+            other => panic!("expected cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn manifest_maps_logical_names() {
+        let dir = scratch_dir("manifest");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#include \"color-utils\"\n#include \"common.vert\"\n").expect("root");
+        let manifest = hashmap!{
+            String::from("color-utils") => PathBuf::from("src/test_glsl/alt/nested.vert"),
+        };
+        let tree = GLSLTree::builder(root.to_str().unwrap())
+            .include_dirs(&["src/test_glsl"])
+            .manifest(manifest)
+            .build()
+            .expect("tree with manifest");
+        assert_eq!(
+            tree.resolved_path_of(root.to_str().unwrap(), 0),
+            Some("src/test_glsl/alt/nested.vert")
+        );
+        assert_eq!(
+            tree.resolved_path_of(root.to_str().unwrap(), 1),
+            Some("src/test_glsl/common.vert")
+        );
+        assert!(tree.render().contains("return 2.0;"));
+
+        match GLSLTree::new(&root, &["src/test_glsl"]) {
+            Err(Error::FailedToOpen { path, .. }) => assert_eq!(path, "color-utils"),
+            other => panic!("expected failure to open, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unsupported_preprocessor_listed() {
+        let dir = scratch_dir("unsupported_preprocessor");
+        fs::write(
+            dir.join("root.vert"),
+            "#version 450\n#define FANCY 1\n#include \"a.vert\"\n#ifdef FANCY\nfloat x;\n#endif\n",
+        )
+        .expect("root");
+        fs::write(dir.join("a.vert"), "  #undef FANCY\n#extension GL_foo : enable\n").expect("a");
+        let tree = GLSLTree::new(dir.join("root.vert"), &[&dir]).expect("tree");
+        let root = dir.join("root.vert").to_str().unwrap().to_string();
+        assert_eq!(
+            tree.uses_unsupported_preprocessor(),
+            vec![
+                (root.clone(), 1, String::from("define")),
+                (root.clone(), 3, String::from("ifdef")),
+                (root, 5, String::from("endif")),
+                (String::from("a.vert"), 0, String::from("undef")),
+            ]
+        );
+
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("tree");
+        assert!(tree.uses_unsupported_preprocessor().is_empty());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn backslash_includes() {
+        let dir = scratch_dir("backslash_includes");
+        let root = dir.join("root.vert");
+        fs::create_dir(dir.join("sub")).expect("sub");
+        fs::write(dir.join("sub/common.glsl"), "float common;\n").expect("common");
+        fs::write(&root, "#include \"sub\\common.glsl\"\n").expect("root");
+        let build = |normalize_backslashes| {
+            GLSLTree::builder(&root)
+                .include_dirs(&[&dir])
+                .normalize_backslashes(normalize_backslashes)
+                .build()
+        };
+
+        match build(false) {
+            Err(error @ Error::BackslashInclude { .. }) => {
+                assert!(error.to_string().starts_with("sub\\common.glsl can't be found"))
+            }
+            other => panic!("expected backslash include, got {:?}", other),
+        }
+        let tree = build(true).expect("normalized tree");
+        assert_eq!(tree.render(), "#version 110\nfloat common;");
+        assert_eq!(
+            tree.resolved_path_of(root.to_str().unwrap(), 0),
+            dir.join("sub/common.glsl").to_str()
+        );
+    }
+
+    #[test]
+    fn includes_traced_in_line_order() {
+        let dir = scratch_dir("line_order");
+        let root = dir.join("root.vert");
+        let includes: String = (0..8)
+            .map(|i| format!("#include \"missing{}.vert\"\n", i))
+            .collect();
+        fs::write(&root, format!("#include \"present.vert\"\n{}", includes)).expect("root");
+        fs::write(dir.join("present.vert"), "float present;\n").expect("present");
+        for _ in 0..4 {
+            match GLSLTree::new(&root, &[&dir]) {
+                Err(Error::FailedToOpen { path, .. }) => assert_eq!(path, "missing0.vert"),
+                other => panic!("expected failure to open, got {:?}", other),
+            }
+        }
+
+        let errors = GLSLTree::validate_collect(&root, &[&dir]).expect("collected errors");
+        let paths: Vec<String> = errors
+            .iter()
+            .map(|error| match error {
+                Error::FailedToOpen { path, .. } => path.clone(),
+                other => panic!("expected failure to open, got {:?}", other),
+            })
+            .collect();
+        let expected: Vec<String> = (0..8).map(|i| format!("missing{}.vert", i)).collect();
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn filtered_includes_skipped() {
+        let dir = scratch_dir("include_filter");
+        let root = dir.join("root.vert");
+        fs::write(
+            &root,
+            "#include \"debug.vert\"\n#include \"lib.vert\"\nfloat r;\n",
+        )
+        .expect("root");
+        fs::write(dir.join("lib.vert"), "#include \"debug.vert\"\nfloat lib;\n").expect("lib");
+        fs::write(dir.join("debug.vert"), "float debug;\n").expect("debug");
+        let build = |filter: fn(&str, &str) -> bool| {
+            GLSLTree::builder(&root)
+                .include_dirs(&[&dir])
+                .include_filter(filter)
+                .build()
+                .expect("filtered tree")
+        };
+
+        let tree = build(|include, _| include != "debug.vert");
+        assert_eq!(tree.render(), "#version 110\nfloat lib;\nfloat r;");
+        assert_eq!(tree.len(), 2);
+
+        // Filtered out where the root includes it, the header is rendered where lib does.
+        let tree = build(|include, includer| include != "debug.vert" || includer == "lib.vert");
+        assert_eq!(tree.render(), "#version 110\nfloat debug;\nfloat lib;\nfloat r;");
+        assert_eq!(
+            tree.include_path_to("debug.vert").expect("include path"),
+            vec![root.to_str().unwrap(), "lib.vert", "debug.vert"]
+        );
+
+        // Filtered includes aren't read, so they needn't exist.
+        fs::remove_file(dir.join("debug.vert")).expect("remove debug");
+        assert_eq!(
+            build(|include, _| include != "debug.vert").render(),
+            "#version 110\nfloat lib;\nfloat r;"
+        );
+    }
+
+    #[test]
+    fn raw_sources_round_trip() {
+        let dir = scratch_dir("render_raw");
+        let root = dir.join("root.vert");
+        let root_src = "#version 330\r\n#include \"a.vert\"\r\n#include \"b.vert\"  \r\nfloat r; ";
+        fs::write(&root, root_src).expect("root");
+        fs::write(dir.join("a.vert"), "float a;\n\n#include \"b.vert\"\n").expect("a");
+        fs::write(dir.join("b.vert"), "#version 330\nfloat b;").expect("b");
+        let build = |preserve_raw| {
+            GLSLTree::builder(&root)
+                .include_dirs(&[&dir])
+                .preserve_raw(preserve_raw)
+                .build()
+                .expect("tree")
+        };
+
+        assert_eq!(build(false).render_raw(), None);
+        let tree = build(true);
+        assert_eq!(
+            tree.render_raw().expect("raw render"),
+            "#version 330\r\nfloat a;\n\n#version 330\nfloat b;\r\n\r\nfloat r; "
+        );
+        assert_eq!(tree.render(), "#version 330\nfloat a;\n\nfloat b;\nfloat r; ");
+
+        let bare = "float x;\r\n\n\r\n  \t";
+        fs::write(&root, bare).expect("bare root");
+        assert_eq!(build(true).render_raw().expect("raw render"), bare);
+
+        // An include continued onto further lines is replaced as a whole.
+        fs::write(dir.join("b.vert"), "float b;").expect("b");
+        fs::write(&root, "#include \\\r\n  \"b.vert\"\r\nfloat r;\n").expect("root");
+        assert_eq!(build(true).render_raw().expect("raw render"), "float b;\r\nfloat r;\n");
+
+        // Raw sources aren't kept when they can't be matched with the transformed ones.
+        let tree = GLSLTree::builder(&root)
+            .include_dirs(&[&dir])
+            .preserve_raw(true)
+            .source_transform(|_, src| format!("// banner\n{}", src))
+            .build()
+            .expect("tree");
+        assert_eq!(tree.render_raw(), None);
+    }
+
+    #[test]
+    fn dot_segments_deduplicated() {
+        let dir = scratch_dir("dot-segments");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#include \"./nested.vert\"\n#include \"nested.vert\"\n").expect("root");
+        let tree = GLSLTree::new(&root, &["src/test_glsl"]).expect("tree");
+        assert_eq!(tree.render(), "#version 110\nfloat someotherfunc() {\n    return 1.0;\n}");
+        assert_eq!(tree.src_map.len(), 2);
+    }
+
+    #[test]
+    fn includes_case_insensitive() {
+        let dir = scratch_dir("case-insensitive");
+        let root = dir.join("root.vert");
+        fs::write(
+            &root,
+            "#include \"Header.vert\"\n#include \"other.vert\"\n#include \"header.VERT\"\n",
+        )
+        .expect("root");
+        fs::write(dir.join("Header.vert"), "float header;\n").expect("header");
+        fs::write(dir.join("other.vert"), "#include \"HEADER.vert\"\nfloat other;\n")
+            .expect("other");
+        let tree = GLSLTree::builder(&root)
+            .include_dirs(&[&dir])
+            .case_insensitive_includes(true)
+            .build()
+            .expect("tree");
+        assert_eq!(tree.render(), "#version 110\nfloat header;\nfloat other;");
+        assert_eq!(tree.src_map.len(), 3);
+    }
+
+    #[test]
+    fn crlf_newlines() {
+        let build = |newline_style| {
+            GLSLTree::builder("src/test_glsl/simple.vert")
+                .include_dirs(&["src/test_glsl"])
+                .newline_style(newline_style)
+                .build()
+                .expect("my tree")
+        };
+        let lf = build(NewlineStyle::Lf);
+        let crlf = build(NewlineStyle::CrLf);
+        assert_eq!(crlf.render(), lf.render().replace('\n', "\r\n"));
+        assert_eq!(crlf.export(), lf.export().replace('\n', "\r\n"));
+        assert_eq!(crlf.render_canonical(), lf.render_canonical());
+        assert!(crlf
+            .rendered_lines_with_origin()
+            .eq(lf.rendered_lines_with_origin()));
+
+        let dir = scratch_dir("crlf-newlines");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#version 150\r\n#include \"header.vert\"\r\nvoid main() {}\r\n")
+            .expect("root");
+        fs::write(dir.join("header.vert"), "float header;\r\n").expect("header");
+        let tree = GLSLTree::builder(&root)
+            .include_dirs(&[&dir])
+            .newline_style(NewlineStyle::CrLf)
+            .build()
+            .expect("tree");
+        assert_eq!(tree.render(), "#version 150\r\nfloat header;\r\nvoid main() {}");
+    }
+
+    #[test]
+    fn root_without_parent() {
+        struct InMemory(Arc<std::sync::Mutex<Vec<PathBuf>>>);
+        impl Resolver for InMemory {
+            fn read(&self, path: &Path) -> std::io::Result<String> {
+                self.0.lock().unwrap().push(path.to_path_buf());
+                match path.to_str() {
+                    Some("frag.glsl") => Ok(String::from("#include \"common.glsl\"\nfloat b;")),
+                    Some("common.glsl") => Ok(String::from("float a;")),
+                    _ => Err(std::io::Error::from(std::io::ErrorKind::NotFound)),
+                }
+            }
+
+            fn modified(&self, _: &Path) -> std::io::Result<Option<std::time::SystemTime>> {
+                Ok(None)
+            }
+        }
+
+        for root in &["frag.glsl", "", "/"] {
+            assert!(GLSLTree::search_dirs(root, &[], false).is_empty());
+        }
+        let reads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let tree = GLSLTree::builder("frag.glsl")
+            .resolver(InMemory(reads.clone()))
+            .build()
+            .expect("tree with a bare root");
+        assert_eq!(tree.render(), "#version 110\nfloat a;\nfloat b;");
+        assert_eq!(
+            *reads.lock().unwrap(),
+            vec![PathBuf::from("frag.glsl"), PathBuf::from("common.glsl")]
+        );
+    }
+
+    #[test]
+    fn unsupported_mtimes_reported() {
+        struct NoMtimes;
+        impl Resolver for NoMtimes {
+            fn read(&self, path: &Path) -> std::io::Result<String> {
+                FsResolver.read(path)
+            }
+
+            fn modified(&self, _: &Path) -> std::io::Result<Option<std::time::SystemTime>> {
+                Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "no mtimes"))
+            }
+        }
+
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .resolver(NoMtimes)
+            .build();
+        match tree {
+            Err(Error::MtimeUnsupported { path }) => {
+                assert_eq!(path, "src/test_glsl/simple.vert")
+            }
+            other => panic!("expected unsupported mtimes, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn expired_files_listed() {
+        let dir = scratch_dir("expired-files");
+        let root = dir.join("root.vert");
+        let header = dir.join("header.vert");
+        fs::write(&root, "#version 150\n#include \"header.vert\"\n").expect("root");
+        fs::write(&header, "float header();\n").expect("header");
+        let tree = GLSLTree::new(&root, &[&dir]).expect("tree");
+        assert!(tree.expired_files().expect("expiry").is_empty());
+        assert!(!tree.expired().expect("expiry"));
+
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&header)
+            .and_then(|file| file.set_modified(later))
+            .expect("touch header");
+        assert_eq!(
+            tree.expired_files().expect("expiry"),
+            vec![String::from(header.to_str().unwrap())]
+        );
+        assert!(tree.expired().expect("expiry"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn origin_paths_use_forward_slashes() {
+        let tree = GLSLTree::new("src\\test_glsl\\simple.vert", &["src\\test_glsl"])
+            .expect("tree");
+        assert_eq!(tree.origin(1), Some(("src/test_glsl/nested.vert", 0)));
+        assert_eq!(tree.origin(9), Some(("src/test_glsl/simple.vert", 2)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_unicode_include_dir_rejected() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = Path::new(OsStr::from_bytes(b"src/\xfftest_glsl"));
+        match GLSLTree::new("src/test_glsl/simple.vert", &[dir]) {
+            Err(Error::NonUnicodePath { path }) => assert_eq!(path, dir),
+            other => panic!("expected non unicode path error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn import_alias() {
+        let plain = GLSLTree::new("src/test_glsl/import.vert", &["src/test_glsl"]).expect("tree");
+        assert!(plain.render().contains("#import \"nested.vert\""));
+
+        let tree = GLSLTree::builder("src/test_glsl/import.vert")
+            .include_dirs(&["src/test_glsl"])
+            .import_alias(true)
+            .build()
+            .expect("tree with imports");
+        assert_eq!(
+            tree.render(),
+            "#version 150\nfloat someotherfunc() {\n    return 1.0;\n}"
+        );
+    }
+
+    #[test]
+    fn include_overrides_pin_resolution() {
+        let dirs = ["src/test_glsl", "src/test_glsl/alt"];
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &dirs).expect("tree");
+        assert!(tree.render().contains("return 1.0;"));
+
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&dirs)
+            .include_overrides(hashmap! {
+                String::from("nested.vert") => String::from("src/test_glsl/alt/nested.vert"),
+            })
+            .build()
+            .expect("tree with overrides");
+        assert!(tree.render().contains("return 2.0;"));
+        assert!(!tree.render().contains("return 1.0;"));
+    }
+
+    #[test]
+    fn affects_output_follows_emission() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("tree");
+        // Included twice, but emitted once.
+        assert!(tree.affects_output("diamond_c.vert"));
+        assert!(tree.affects_output("src/test_glsl/diamond_c.vert"));
+        assert!(tree.affects_output("src/test_glsl/simple.vert"));
+        assert!(!tree.affects_output("fancy.vert"));
+
+        let mut tree = GLSLTree::builder("src/test_glsl/conditional.vert")
+            .include_dirs(&["src/test_glsl"])
+            .prune_conditionals(true)
+            .build()
+            .expect("conditional tree");
+        assert!(tree.affects_output("nested.vert"));
+        tree.set_defines(&[("FANCY", ""), ("QUALITY", "2")]);
+        assert!(!tree.affects_output("nested.vert"));
+    }
+
+    #[test]
+    fn include_dirs_normalized() {
+        let plain = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("tree");
+        let slashed =
+            GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl//"]).expect("tree");
+        assert_eq!(slashed.include_dirs, vec![String::from("src/test_glsl")]);
+        assert_eq!(slashed.render(), plain.render());
+        assert_eq!(slashed.render().matches("duplicate_symbol").count(), 1);
+        assert_eq!(slashed.origin(4), Some(("src/test_glsl/diamond_c.vert", 0)));
+    }
+
+    #[test]
+    fn sources_transformed() {
+        let dir = scratch_dir("source-transform");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#version 150\n@nested@\nvoid main() {}\n").expect("root");
+        let tree = GLSLTree::builder(&root)
+            .include_dirs(&["src/test_glsl"])
+            .source_transform(|path, src| {
+                assert!(path.ends_with(".vert"));
+                src.replace("@nested@", "#include \"nested.vert\"")
+            })
+            .build()
+            .expect("transformed tree");
+        assert_eq!(
+            tree.render(),
+            "#version 150\nfloat someotherfunc() {\n    return 1.0;\n}\nvoid main() {}"
+        );
+    }
+
+    #[test]
+    fn include_resolution_callback() {
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .resolve_include(|include, includer| {
+                if include == "nested.vert" && includer == "common.vert" {
+                    Some(String::from("src/test_glsl/alt/nested.vert"))
+                } else {
+                    None
+                }
+            })
+            .build()
+            .expect("tree with resolver");
+        assert!(tree.render().contains("return 2.0;"));
+        assert!(tree.render().contains("duplicate_symbol"));
+    }
+
+    #[test]
+    fn includes_next_to_root_without_include_dirs() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &[] as &[&str])
+            .expect("tree without include dirs");
+        let with_dirs =
+            GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        assert_eq!(tree.render(), with_dirs.render());
+    }
+
+    #[test]
+    fn file_info() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        let info = tree.file_info("common.vert").expect("info by key");
+        assert_eq!(
+            info.path,
+            std::env::current_dir()
+                .expect("current dir")
+                .join("src/test_glsl/common.vert")
+        );
+        assert_eq!(info.version, None);
+        assert_eq!(
+            info.includes,
+            vec![
+                (0, String::from("nested.vert")),
+                (1, String::from("diamond_a.vert")),
+                (2, String::from("diamond_b.vert")),
+            ]
+        );
+        assert!(info.mtime.is_some());
+        assert_eq!(tree.file_info("src/test_glsl/common.vert"), Some(info));
+
+        let root = tree.file_info("src/test_glsl/simple.vert").expect("root info");
+        assert_eq!(root.version, Some(150));
+        assert!(tree.file_info("unknown.vert").is_none());
+    }
+
+    #[test]
+    fn license_headers_stripped() {
+        let tree = GLSLTree::builder("src/test_glsl/licensed.vert")
+            .include_dirs(&["src/test_glsl"])
+            .strip_license_headers(true)
+            .build()
+            .expect("tree without headers");
+        assert_eq!(
+            tree.render(),
+            "#version 150\n\
+             // Copyright (c) Example Authors.\n\
+             // Licensed under the MIT license.\n\
+             \n\
+             float a() { return 1.0; }\n\
+             float b() { return 2.0; } // not a header"
+        );
+
+        let tree = GLSLTree::new("src/test_glsl/licensed.vert", &["src/test_glsl"])
+            .expect("tree with headers");
+        assert_eq!(tree.render().matches("Copyright").count(), 3);
+    }
+
+    #[test]
+    fn include_path_to_file() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        let chain = |path| tree.include_path_to(path);
+        assert_eq!(
+            chain("diamond_c.vert"),
+            Some(vec![
+                String::from("src/test_glsl/simple.vert"),
+                String::from("common.vert"),
+                String::from("diamond_a.vert"),
+                String::from("diamond_c.vert"),
+            ])
+        );
+        assert_eq!(chain("src/test_glsl/nested.vert").map(|c| c.len()), Some(3));
+        assert_eq!(chain("src/test_glsl/simple.vert").map(|c| c.len()), Some(1));
+        assert_eq!(chain("unknown.vert"), None);
+    }
+
+    #[test]
+    fn back_edges_deduplicated() {
+        let tree = GLSLTree::builder("src/test_glsl/back_edge.vert")
+            .include_dirs(&["src/test_glsl"])
+            .allow_back_edges(true)
+            .build()
+            .expect("tree with back edge");
+        assert_eq!(
+            tree.render(),
+            "#version 150\nconst float CONFIG = 1.0;\nvoid main() {}"
+        );
+
+        match GLSLTree::new("src/test_glsl/back_edge.vert", &["src/test_glsl"]) {
+            Err(Error::IncludesRoot { .. }) => (),
+            other => panic!("expected root inclusion, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn root_inclusion_detected() {
+        let error = GLSLTree::new("src/test_glsl/back_edge.vert", &["src/test_glsl"])
+            .expect_err("root inclusion");
+        assert_eq!(
+            error.to_string(),
+            "back_edge_config.vert includes the root on line 1; \
+             did you mean to include another file?"
+        );
+
+        let dir = scratch_dir("includes-root");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#include \"header.vert\"\n").expect("root");
+        fs::write(dir.join("header.vert"), "float a;\n#include \"root.vert\"\n").expect("header");
+        let includer = |tree: Result<GLSLTree>| match tree {
+            Err(Error::IncludesRoot { path, line }) => (path, line),
+            other => panic!("expected root inclusion, got {:?}", other),
+        };
+        assert_eq!(includer(GLSLTree::new(&root, &[&dir])), (String::from("header.vert"), 1));
+        let root = root.to_str().unwrap();
+        fs::write(dir.join("header.vert"), format!("#include \"{}\"\n", root)).expect("header");
+        assert_eq!(includer(GLSLTree::new(root, &[&dir])), (String::from("header.vert"), 0));
+    }
+
+    #[test]
+    fn shared_dependencies() {
+        let simple = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("tree");
+        let import = GLSLTree::new("src/test_glsl/import.vert", &["src/test_glsl"]).expect("tree");
+        assert_eq!(simple.shared_dependencies(&import), vec![String::from("nested.vert")]);
+        assert_eq!(import.shared_dependencies(&simple), vec![String::from("nested.vert")]);
+        assert_eq!(simple.shared_dependencies(&simple).len(), simple.src_map.len());
+    }
+
+    #[test]
+    fn versions() {
+        let tree =
+            GLSLTree::with_default_version("src/test_glsl/simple.vert", &["src/test_glsl"], 300)
+                .expect("my tree");
+        assert_eq!((tree.version(), tree.default_version()), (150, 300));
+        let tree = GLSLTree::new("src/test_glsl/shader", &["src/test_glsl"]).expect("tree");
+        assert_eq!((tree.version(), tree.default_version()), (150, 110));
+        let tree = GLSLTree::new("src/test_glsl/common.vert", &["src/test_glsl"]).expect("tree");
+        assert_eq!((tree.version(), tree.default_version()), (110, 110));
+    }
+
+    #[test]
+    fn es_profile() {
+        let tree = |src: &str, default_profile: Option<Profile>| {
+            let dir = scratch_dir("es-profile");
+            let root = dir.join("root.vert");
+            fs::write(&root, src).expect("root");
+            let builder = GLSLTree::builder(&root).default_version(100);
+            match default_profile {
+                Some(profile) => builder.default_profile(profile),
+                None => builder,
+            }
+            .build()
+            .expect("tree")
+        };
+        let es = tree("#version 300 es\nvoid main() {}\n", None);
+        assert!(es.is_es());
+        assert!(es.render().starts_with("#version 300 es\n"));
+        assert!(!tree("#version 330 core\n", None).is_es());
+        assert!(!tree("#version 300\n", None).is_es());
+        assert!(tree("#version 100\n", None).is_es());
+        assert!(tree("void main() {}\n", None).is_es());
+
+        let defaulted = tree("void main() {}\n", Some(Profile::Core));
+        assert!(defaulted.render().starts_with("#version 100 core\n"));
+        assert!(!tree("#version 310\n", Some(Profile::Es)).is_es());
+    }
+
+    #[test]
+    fn directives_listed() {
+        let tree = GLSLTree::builder("src/test_glsl/conditional.vert")
+            .include_dirs(&["src/test_glsl"])
+            .defines(&[("FANCY", ""), ("QUALITY", "2")])
+            .prune_conditionals(true)
+            .build()
+            .expect("tree");
+        let directives = tree.directives_of("src/test_glsl/conditional.vert").expect("root");
+        assert_eq!(
+            directives[0],
+            DirectiveInfo {
+                line: 0,
+                kind: DirectiveKind::Version {
+                    version: 330,
+                    profile: None,
+                },
+            }
+        );
+        assert_eq!(directives.len(), 8);
+        assert!(directives.windows(2).all(|pair| pair[0].line < pair[1].line));
+        assert!(directives.iter().any(|directive| {
+            directive.kind == DirectiveKind::Include {
+                path: String::from("missing_plain.vert"),
+            }
+        }));
+        assert!(directives
+            .iter()
+            .any(|directive| directive.kind == DirectiveKind::Conditional(Conditional::Else)));
+        assert_eq!(tree.directives_of("fancy.vert"), Some(Vec::new()));
+        assert_eq!(tree.directives_of("unknown.vert"), None);
+    }
+
+    #[test]
+    fn version_required() {
+        let build = |path| {
+            GLSLTree::builder(path)
+                .include_dirs(&["src/test_glsl"])
+                .require_version(true)
+                .build()
+        };
+        assert!(build("src/test_glsl/simple.vert").is_ok());
+        match build("src/test_glsl/common.vert") {
+            Err(Error::MissingVersion { path }) => assert_eq!(path, "src/test_glsl/common.vert"),
+            other => panic!("expected missing version, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        assert_eq!(
+            tree.stats(),
+            Stats {
+                traced_count: 6,
+                emitted_count: 4,
+            }
+        );
+        assert_eq!(tree.len(), 6);
+        assert!(!tree.is_empty());
+        assert_eq!(tree.max_depth(), 3);
+        let tree = GLSLTree::new("src/test_glsl/nested.vert", &[] as &[&str]).expect("leaf tree");
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.max_depth(), 0);
+    }
+
+    #[test]
+    fn max_depth_reached() {
+        let dir = scratch_dir("max_depth");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#include \"c.vert\"\n#include \"a.vert\"\n").expect("root");
+        fs::write(dir.join("a.vert"), "#include \"b.vert\"\n").expect("a");
+        fs::write(dir.join("b.vert"), "#include \"c.vert\"\n").expect("b");
+        fs::write(dir.join("c.vert"), "float c;\n").expect("c");
+        let tree = GLSLTree::new(&root, &[&dir]).expect("tree");
+        assert_eq!(tree.max_depth(), 3);
+        assert_eq!(tree.reroot("a.vert").expect("rerooted tree").max_depth(), 2);
+    }
+
+    #[test]
+    fn unreached_includes_warned() {
+        let tree = GLSLTree::new("src/test_glsl/deduped.vert", &["src/test_glsl"]).expect("tree");
+        assert_eq!(
+            tree.warnings(),
+            vec![Warning::UnreachedInclude {
+                path: String::from("secondary.vert"),
+            }]
+        );
+
+        let tree = GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        assert_eq!(
+            tree.warnings(),
+            vec![Warning::UnreachedInclude {
+                path: String::from("diamond_b.vert"),
+            }]
+        );
+    }
+
+    #[test]
+    fn roots_with_any_extension() {
+        for root in &["src/test_glsl/shader", "src/test_glsl/shader.vs"] {
+            let tree = GLSLTree::new(root, &["src/test_glsl"]).expect("tree");
+            assert_eq!(
+                tree.render(),
+                "#version 150\nfloat someotherfunc() {\n    return 1.0;\n}"
+            );
+            assert_eq!(tree.line_contributions()[0], (String::from(*root), 0));
+        }
+    }
+
+    #[test]
+    fn line_directives_kept_consistent() {
+        let dir = scratch_dir("line-directives");
+        let root = dir.join("root.vert");
+        fs::write(
+            &root,
+            "#version 150\n\
+             #line 100 2\n\
+             float a;\n\
+             #include \"header.vert\"\n\
+             float b;\n\
+             float c;\n",
+        )
+        .expect("root");
+        fs::write(dir.join("header.vert"), "float h1;\nfloat h2;\n").expect("header");
+        let tree = GLSLTree::new(&root, &[&dir]).expect("tree");
+        assert_eq!(
+            tree.render(),
+            "#version 150\n\
+             #line 100 2\n\
+             float a;\n\
+             float h1;\n\
+             float h2;\n\
+             #line 102 2\n\
+             float b;\n\
+             float c;"
+        );
+        // The synthesized directive isn't a line of the source.
+        assert_eq!(tree.origin(5), None);
+        assert_eq!(tree.origin(6), Some((root.to_str().unwrap(), 4)));
+        let directives = tree.directives_of(root.to_str().unwrap()).expect("root");
+        assert_eq!(
+            directives[1].kind,
+            DirectiveKind::Line {
+                number: 100,
+                source_string: Some(2),
+            }
+        );
+
+        // Lines which stay in place need no directive.
+        fs::write(&root, "#line 10\nfloat a;\n#include \"one.vert\"\nfloat b;\n").expect("root");
+        fs::write(dir.join("one.vert"), "float one;\n").expect("one");
+        let tree = GLSLTree::new(&root, &[&dir]).expect("tree");
+        assert_eq!(tree.render(), "#version 110\n#line 10\nfloat a;\nfloat one;\nfloat b;");
+    }
+
+    #[test]
+    fn default_profile_inheritance() {
+        let dir = scratch_dir("inherit-profile");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#version 310\nvoid main() {}\n").expect("root");
+        let tree = |inherit| {
+            GLSLTree::builder(&root)
+                .default_profile(Profile::Es)
+                .inherit_default_profile(inherit)
+                .build()
+                .expect("tree")
+        };
+        let inherited = tree(true);
+        assert_eq!(inherited.render(), "#version 310 es\nvoid main() {}");
+        assert!(inherited.is_es());
+        let as_written = tree(false);
+        assert_eq!(as_written.render(), "#version 310\nvoid main() {}");
+        assert!(!as_written.is_es());
+
+        fs::write(&root, "#version 330 core\n").expect("root");
+        assert_eq!(tree(true).render(), "#version 330 core");
+    }
+
+    #[test]
+    fn rendered_lines_with_origins() {
+        let tree = GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .defines(&[("FAST", "")])
+            .build()
+            .expect("tree with defines");
+        let lines: Vec<(&str, &str, usize)> = tree.rendered_lines_with_origin().collect();
+        assert_eq!(lines.len(), tree.render().split('\n').count() - 2);
+        assert_eq!(lines[0], ("float someotherfunc() {", "src/test_glsl/nested.vert", 0));
+        assert_eq!(lines.last(), Some(&("}", "src/test_glsl/simple.vert", 8)));
+        for (line, path, i) in lines {
+            assert_eq!(std::fs::read_to_string(path).unwrap().lines().nth(i), Some(line));
+        }
+    }
+
+    #[test]
+    fn version_mismatch_allowed_for_listed_files() {
+        let dir = scratch_dir("version-mismatch-allowed");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#version 330\n#include \"old.vert\"\n#include \"older.vert\"\n")
+            .expect("root");
+        fs::write(dir.join("old.vert"), "#version 150\nfloat old;\n").expect("old");
+        fs::write(dir.join("older.vert"), "#version 130\nfloat older;\n").expect("older");
+        let build = |allowed: &[&str]| {
+            GLSLTree::builder(&root)
+                .include_dirs(&[&dir])
+                .allow_version_mismatch_for(allowed)
+                .build()
+        };
+
+        let older = dir.join("older.vert");
+        let tree = build(&["old.vert", older.to_str().unwrap()]).expect("tree");
+        assert_eq!(tree.render(), "#version 330\nfloat old;\nfloat older;");
+        match build(&["old.vert"]) {
+            Err(Error::VersionMismatch { src_version, .. }) => assert_eq!(src_version, 130),
+            other => panic!("expected version mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn version_policies() {
+        let dir = scratch_dir("version-policies");
+        let root = dir.join("root.vert");
+        fs::write(&root, "#version 330\n#include \"old.vert\"\n#include \"new.vert\"\n")
+            .expect("root");
+        fs::write(dir.join("old.vert"), "#version 150\nfloat old;\n").expect("old");
+        fs::write(dir.join("new.vert"), "#version 450\nfloat new;\n").expect("new");
+        let build = |version_policy| {
+            GLSLTree::builder(&root)
+                .include_dirs(&[&dir])
+                .version_policy(version_policy)
+                .build()
+        };
+
+        match build(VersionPolicy::Strict) {
+            Err(Error::VersionMismatch { .. }) => (),
+            other => panic!("expected version mismatch, got {:?}", other),
+        }
+
+        let tree = build(VersionPolicy::RootWins).expect("root wins");
+        assert_eq!(tree.render(), "#version 330\nfloat old;\nfloat new;");
+        let warnings = tree.warnings();
+        assert_eq!(
+            warnings,
+            vec![
+                Warning::VersionOverridden {
+                    path: String::from("new.vert"),
+                    declared: 450,
+                    rendered: 330,
+                },
+                Warning::VersionOverridden {
+                    path: String::from("old.vert"),
+                    declared: 150,
+                    rendered: 330,
+                },
+            ]
+        );
+        assert_eq!(
+            warnings[1].to_string(),
+            "old.vert declares version 150 but is rendered as version 330"
+        );
+
+        let tree = build(VersionPolicy::Highest).expect("highest");
+        assert_eq!(tree.version(), 450);
+        assert_eq!(tree.render(), "#version 450\nfloat old;\nfloat new;");
+        assert_eq!(tree.warnings().len(), 2);
+        assert!(tree.warnings().iter().any(|warning| match warning {
+            Warning::VersionOverridden { path, declared, .. } => {
+                *path == root.to_str().unwrap() && *declared == 330
+            }
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn strict_version_placement() {
+        match GLSLTree::builder("src/test_glsl/misplaced_version.vert")
+            .strict_version(true)
+            .build()
+        {
+            Err(Error::MisplacedVersion { line }) => assert_eq!(line, 3),
+            other => panic!("expected misplaced version error, got {:?}", other),
+        }
+        GLSLTree::new("src/test_glsl/misplaced_version.vert", &[] as &[&str])
+            .expect("lenient tree");
+        GLSLTree::builder("src/test_glsl/commented_version.vert")
+            .strict_version(true)
+            .build()
+            .expect("version after comments");
+    }
+
+    #[test]
+    fn include_suffixes_stripped() {
+        GLSLTree::new("src/test_glsl/suffixed.vert", &["src/test_glsl"])
+            .expect_err("suffixed include is not found by default");
+        let tree = GLSLTree::builder("src/test_glsl/suffixed.vert")
+            .include_dirs(&["src/test_glsl"])
+            .strip_include_suffixes(&["?"])
+            .build()
+            .expect("suffixed tree");
+        assert_eq!(
+            tree.render(),
+            "#version 150\nfloat someotherfunc() {\n    return 1.0;\n}"
+        );
+    }
+
+    #[test]
+    fn error_paths_relative_to_base() {
+        let root = std::env::current_dir()
+            .expect("working dir")
+            .join("src/test_glsl/missing.vert");
+        let error = GLSLTree::new(&root, &[] as &[&str]).expect_err("missing root");
+        assert!(error.to_string().contains(root.to_str().unwrap()));
+        assert!(error
+            .relative_to(std::env::current_dir().expect("working dir"))
+            .to_string()
+            .starts_with("Failed to open src/test_glsl/missing.vert after searching"));
+
+        let relative = |error: Error| error.relative_to("/proj").to_string();
+        assert_eq!(
+            relative(Error::TooManyIncludes {
+                path: String::from("/proj/sh/many.vert"),
+                count: 2,
+            }),
+            "sh/many.vert has 2 includes, more than the configured limit"
+        );
+        assert_eq!(
+            relative(Error::InvalidIncludeDir {
+                path: String::from("/proj/include"),
+            }),
+            "Include directory include does not exist or is not a directory"
+        );
+        assert_eq!(
+            relative(Error::NonUnicodePath {
+                path: PathBuf::from("/proj/include"),
+            }),
+            "Path \"include\" is not valid unicode"
+        );
+        assert_eq!(
+            relative(Error::MalformedInclude {
+                path: String::from("/proj/sh/a.vert"),
+                line: 0,
+            }),
+            "The include on line 1 of sh/a.vert must be a single quoted or bracketed path"
+        );
+        assert_eq!(
+            relative(Error::IncludeEscapesSandbox {
+                path: String::from("/proj/other/a.vert"),
+            }),
+            "other/a.vert is outside the sandbox root"
+        );
+        assert_eq!(
+            relative(Error::MissingVersion {
+                path: String::from("/proj/sh/a.vert"),
+            }),
+            "sh/a.vert has no version pragma"
+        );
+        assert_eq!(
+            relative(Error::DuplicateVersion {
+                path: String::from("/proj/sh/a.vert"),
+                lines: vec![0, 4],
+            }),
+            "sh/a.vert has more than one version pragma, on lines 1, 5"
+        );
+        assert_eq!(
+            relative(Error::IncludesRoot {
+                path: String::from("/proj/sh/a.vert"),
+                line: 2,
+            }),
+            "sh/a.vert includes the root on line 3; did you mean to include another file?"
+        );
+        assert!(relative(Error::MtimeUnsupported {
+            path: String::from("/proj/sh/a.vert"),
+        })
+        .starts_with("Modification times are unavailable for sh/a.vert,"));
+        assert_eq!(
+            relative(Error::EmptyOutput {
+                path: String::from("/proj/sh/a.vert"),
+            }),
+            "The tree rooted at sh/a.vert renders no code"
+        );
+        assert_eq!(
+            relative(Error::FileTooLarge {
+                path: String::from("/proj/sh/a.vert"),
+                size: 20,
+            }),
+            "sh/a.vert is 20 bytes, more than the configured limit"
+        );
+        assert_eq!(
+            relative(Error::OutsideRequiredDir {
+                path: String::from("/proj/other/a.vert"),
+                dir: String::from("/proj/sh"),
+            }),
+            "other/a.vert resolves outside sh, which every file is required to be within"
+        );
+        assert!(relative(Error::BackslashInclude {
+            path: String::from("/proj/sh\\a.vert"),
+        })
+        .starts_with("sh\\a.vert can't be found"));
+    }
+
+    #[test]
+    fn root_from_reader() {
+        let src = "#version 150\n#include \"nested.vert\"\n";
+        let tree =
+            GLSLTree::from_reader(src.as_bytes(), &["src/test_glsl"], 110).expect("reader tree");
+        assert_eq!(
+            tree.render(),
+            "#version 150\nfloat someotherfunc() {\n    return 1.0;\n}"
+        );
+        assert!(!tree.expired().expect("expiry"));
+        let refreshed = tree.clone().refresh().expect("refreshed tree");
+        assert_eq!(refreshed.render(), tree.render());
+    }
+
+    #[test]
+    fn include_count_limit() {
+        match GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&["src/test_glsl"])
+            .max_includes_per_file(2)
+            .build()
+        {
+            Err(Error::TooManyIncludes { path, count }) => {
+                assert_eq!(path, "common.vert");
+                assert_eq!(count, 3);
+            }
+            other => panic!("expected too many includes error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn include_dirs_validated() {
+        let dirs = ["src/test_glsl", "src/no_such_dir"];
+        GLSLTree::new("src/test_glsl/simple.vert", &dirs).expect("unvalidated dirs");
+        match GLSLTree::builder("src/test_glsl/simple.vert")
+            .include_dirs(&dirs)
+            .validate_include_dirs(true)
+            .build()
+        {
+            Err(Error::InvalidIncludeDir { path }) => assert_eq!(path, "src/no_such_dir"),
+            other => panic!("expected invalid include dir error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn extensions_hoisted() {
+        let tree = GLSLTree::builder("src/test_glsl/extension.vert")
+            .include_dirs(&["src/test_glsl"])
+            .hoist_extensions(true)
+            .build()
+            .expect("extension tree");
+        let lines: Vec<&str> = tree.render().lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "#version 330",
+                "#extension GL_OES_standard_derivatives : enable",
+                "#extension GL_ARB_shading_language_420pack : require",
+                "float a() {",
+                "    return 1.0;",
+                "}",
+                "float header() {",
+                "    return 2.0;",
+                "}",
+                "",
+                "void main() {",
+                "}",
+            ]
+        );
+    }
+
+    #[test]
+    fn guarded_extensions_left_in_place() {
+        let dir = scratch_dir("guarded_extensions");
+        let root = dir.join("root.vert");
+        fs::write(
+            &root,
+            "#version 100\n#include \"lib.vert\"\n\
+             #ifdef GL_OES_standard_derivatives\n\
+             #extension GL_OES_standard_derivatives : enable\n\
+             #endif\n\
+             void main() {}\n",
+        )
+        .expect("root");
+        fs::write(dir.join("lib.vert"), "#extension GL_EXT_frag_depth : enable\nfloat lib;\n")
+            .expect("lib");
+        let tree = GLSLTree::builder(&root)
+            .include_dirs(&[&dir])
+            .hoist_extensions(true)
+            .build()
+            .expect("tree");
+        assert_eq!(
+            tree.render(),
+            "#version 100\n\
+             #extension GL_EXT_frag_depth : enable\n\
+             float lib;\n\
+             #ifdef GL_OES_standard_derivatives\n\
+             #extension GL_OES_standard_derivatives : enable\n\
+             #endif\n\
+             void main() {}"
+        );
     }
 }
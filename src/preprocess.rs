@@ -1,40 +1,103 @@
+use builder::{AmbiguityPolicy, Options};
+use conditional::Conditional;
 use {Error, Result};
 use regex::Regex;
-use std::collections::HashMap;
-use std::io::Read;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::iter;
+use std::ops::Range;
 use std::time::SystemTime;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 lazy_static! {
-    static ref INCLUDE_RE: Regex = Regex::new(r#"("|<)([^">]+)("|>)$"#).unwrap();
-    static ref VERSION_RE: Regex = Regex::new(r#"\s*(\d\d\d)\s*$"#).unwrap();
+    // Whitespace, including a stray `\r`, and a comment may follow the closing delimiter.
+    static ref INCLUDE_RE: Regex =
+        Regex::new(r#"^\s*(?:"([^"]+)"|<([^>]+)>)\s*(?://.*|/\*.*)?$"#).unwrap();
+    static ref VERSION_RE: Regex =
+        Regex::new(r#"\s*(\d\d\d)(?:\s+(core|compatibility|es))?\s*$"#).unwrap();
+    static ref LINE_RE: Regex = Regex::new(r#"^\s*(\d+)(?:\s+(\d+))?\s*$"#).unwrap();
+    static ref EXTENSION_RE: Regex = Regex::new(r#"^\s*(\w+)\s*:\s*(\w+)\s*$"#).unwrap();
 }
 
+/// Keywords of directives left for the compiler's preprocessor. Conditionals are evaluated
+/// to decide which includes to trace, but their branches are still rendered.
+const PASSTHROUGH_KEYWORDS: [&str; 9] =
+    ["define", "undef", "if", "ifdef", "ifndef", "elif", "else", "endif", "error"];
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct AnnotatedGLSL {
     pub lines: Vec<String>,
     pub version_pragma: Option<(usize, usize)>,
+    /// The profile of the version pragma, if it names one.
+    pub profile: Option<Profile>,
     pub includes: HashMap<usize, String>,
-    pub mtime: SystemTime,
+    /// Line indices of includes which are skipped if their file can't be found.
+    pub optional_includes: HashSet<usize>,
+    /// Conditional directives, keyed by line index.
+    pub conditionals: HashMap<usize, Conditional>,
+    /// Line numbers and source string numbers of `#line` directives, keyed by line index.
+    pub line_directives: HashMap<usize, (usize, Option<usize>)>,
+    /// Extension names and behaviors, keyed by line index.
+    pub extensions: HashMap<usize, (String, String)>,
+    /// Byte offset of the start of each directive line, keyed by line index.
+    pub directive_offsets: HashMap<usize, usize>,
+    /// Byte range of each include path (between its delimiters), keyed by line index.
+    pub include_spans: HashMap<usize, Range<usize>>,
+    /// The deepest include nesting the file was traced at, the root being at depth 0.
+    pub depth: usize,
+    pub mtime: Option<SystemTime>,
+    /// The source exactly as read, if raw sources are kept and there is no source transform.
+    pub raw: Option<String>,
     pub path: String,
+    /// `path` with forward slashes, for paths written to output, which GLSL tooling expects
+    /// to be POSIX style even on Windows.
+    pub portable_path: String,
 }
 
 impl AnnotatedGLSL {
-    pub fn load(path: &str, search_dirs: &[String]) -> Result<AnnotatedGLSL> {
-        let (mut file, found_path) = search_dirs
-            .iter()
-            .fold(
-                File::open(&path).map(|f| (f, PathBuf::from(String::from(path)))),
-                |r, include_dir| {
-                    r.or_else(|_| {
-                        let mut prefixed_path = PathBuf::new();
-                        prefixed_path.push(&include_dir);
-                        prefixed_path.push(&path);
-                        Ok((File::open(&prefixed_path)?, prefixed_path))
-                    })
+    pub fn load(path: &str, search_dirs: &[String], options: &Options) -> Result<AnnotatedGLSL> {
+        let resolver = &options.resolver.0;
+        // The last failure to read a candidate is reported if none can be read.
+        let mut found = Err(io::Error::from(io::ErrorKind::NotFound));
+        for candidate in candidates(path, search_dirs) {
+            let candidate_path = candidate.to_str().unwrap();
+            let read = match options.read_override {
+                Some(ref read_override) => (read_override.0)(candidate_path),
+                None => None,
+            };
+            let read = match read {
+                // Overridden sources have no file to go stale.
+                Some(read) => read.map(|src| (src, None)),
+                None => {
+                    if let Ok(Some(size)) = resolver.size(&candidate) {
+                        if size > options.max_file_size {
+                            return Err(Error::FileTooLarge {
+                                path: String::from(candidate_path),
+                                size,
+                            });
+                        }
+                    }
+                    match resolver.read(&candidate) {
+                        Ok(src) => Ok((src, modified(options, candidate_path)?)),
+                        Err(error) => Err(error),
+                    }
+                }
+            };
+            match read {
+                Ok((src, mtime)) => match (options.ambiguity_policy, &found) {
+                    (AmbiguityPolicy::Newest, Ok((_, _, newest))) if *newest >= mtime => (),
+                    (AmbiguityPolicy::Newest, _) => found = Ok((src, candidate, mtime)),
+                    (AmbiguityPolicy::FirstFound, _) => {
+                        found = Ok((src, candidate, mtime));
+                        break;
+                    }
                 },
-            )
+                Err(error) if found.is_err() => found = Err(error),
+                Err(_) => (),
+            }
+        }
+        let (src, found_path, mtime) = found
             .map_err(|e| Error::FailedToOpen {
                 path: path.to_string(),
                 searched_dirs: {
@@ -44,64 +107,443 @@ impl AnnotatedGLSL {
                 },
                 cause: e,
             })?;
-        let mut src = String::new();
-        let _ = file.read_to_string(&mut src)?;
+        let found_path = String::from(found_path.to_str().unwrap());
+        // Raw sources can't be matched line for line against transformed ones.
+        let raw = if options.preserve_raw && options.source_transform.is_none() {
+            Some(src.clone())
+        } else {
+            None
+        };
+        let src = match options.source_transform {
+            Some(ref transform) => (transform.0)(&found_path, &src),
+            None => src,
+        };
+        let mut annotated = AnnotatedGLSL::parse(&src, found_path, mtime, options)?;
+        annotated.raw = raw;
+        Ok(annotated)
+    }
+
+    /// Returns the path `load` would read the given path from, without reading it, or
+    /// `None` if no candidate exists.
+    pub fn locate(path: &str, search_dirs: &[String], options: &Options) -> Option<String> {
+        let resolver = &options.resolver.0;
+        let mut existing = candidates(path, search_dirs).filter(|path| {
+            let overridden = match options.read_override {
+                Some(ref read_override) => (read_override.0)(path.to_str().unwrap()),
+                None => None,
+            };
+            match overridden {
+                Some(read) => read.is_ok(),
+                None => resolver.exists(path),
+            }
+        });
+        let found = match options.ambiguity_policy {
+            AmbiguityPolicy::FirstFound => existing.next(),
+            // `max_by_key` would prefer the last of equally new candidates.
+            AmbiguityPolicy::Newest => existing
+                .map(|path| (resolver.modified(&path).ok().flatten(), path))
+                .fold(None, |newest: Option<(Option<SystemTime>, PathBuf)>, candidate| {
+                    match newest {
+                        Some(newest) if newest.0 >= candidate.0 => Some(newest),
+                        _ => Some(candidate),
+                    }
+                })
+                .map(|(_, path)| path),
+        };
+        found.and_then(|path| path.to_str().map(String::from))
+    }
 
-        let lines: Vec<String> = src.lines().map(String::from).collect();
+    /// Annotates GLSL source which did not necessarily come from a file. Source without an
+    /// `mtime` never expires.
+    ///
+    /// A directive line ending in a backslash continues onto the next line, as in the C
+    /// preprocessor. The continued lines are joined onto the directive's line and left
+    /// blank, so the lines after them keep their numbers.
+    pub fn parse(
+        src: &str,
+        path: String,
+        mtime: Option<SystemTime>,
+        options: &Options,
+    ) -> Result<AnnotatedGLSL> {
+        let mut lines: Vec<String> = src.lines().map(String::from).collect();
+        let line_starts: Vec<usize> = src
+            .split_inclusive('\n')
+            .scan(0, |offset, line| {
+                let start = *offset;
+                *offset += line.len();
+                Some(start)
+            })
+            .collect();
+        let continuations = join_continuations(&mut lines, &line_starts);
+        // Maps an offset within a line to an offset within the source.
+        let source_offset = |i: usize, offset: usize| match continuations.get(&i) {
+            Some(segments) => {
+                let &(start, source_start) =
+                    segments.iter().rev().find(|(start, _)| *start <= offset).unwrap();
+                source_start + offset - start
+            }
+            None => line_starts[i] + offset,
+        };
         let mut version_pragma = None;
+        let mut version_lines = Vec::new();
+        let mut profile = None;
         let mut includes = HashMap::new();
-        for i in 0..(lines.len()) {
-            match directive(&lines[i]) {
-                Some(Directive::Version(version)) => version_pragma = Some((i, version)),
-                Some(Directive::Include(path)) => {
-                    includes.insert(i, path);
+        let mut optional_includes = HashSet::new();
+        let mut conditionals = HashMap::new();
+        let mut line_directives = HashMap::new();
+        let mut extensions = HashMap::new();
+        let mut directive_offsets = HashMap::new();
+        let mut include_spans = HashMap::new();
+        for (i, line) in lines.iter().enumerate() {
+            let directive = directive(line, options);
+            if directive.is_some() {
+                directive_offsets.insert(i, line_starts[i]);
+            }
+            match directive {
+                Some(Directive::Version(version, version_profile)) => {
+                    version_lines.push(i);
+                    version_pragma = Some((i, version));
+                    profile = version_profile;
+                }
+                Some(Directive::Include(path, span, optional)) => {
+                    includes.insert(i, normalize_include(path, options));
+                    if optional {
+                        optional_includes.insert(i);
+                    }
+                    // Paths are never empty, so the last byte of the span is within it.
+                    let end = source_offset(i, span.end - 1) + 1;
+                    include_spans.insert(i, source_offset(i, span.start)..end);
+                }
+                Some(Directive::Line(number, source_string)) => {
+                    line_directives.insert(i, (number, source_string));
+                }
+                Some(Directive::Extension(name, behavior)) => {
+                    extensions.insert(i, (name, behavior));
+                }
+                Some(Directive::Conditional(conditional)) => {
+                    conditionals.insert(i, conditional);
+                }
+                Some(Directive::MalformedInclude) => {
+                    return Err(Error::MalformedInclude { path, line: i });
                 }
                 None => (),
             };
         }
+        if version_lines.len() > 1 {
+            return Err(Error::DuplicateVersion {
+                path,
+                lines: version_lines,
+            });
+        }
         Ok(AnnotatedGLSL {
             lines,
             version_pragma,
+            profile,
             includes,
-            mtime: file.metadata()?.modified()?,
-            path: String::from(found_path.to_str().unwrap()),
+            optional_includes,
+            conditionals,
+            line_directives,
+            extensions,
+            directive_offsets,
+            include_spans,
+            depth: 0,
+            mtime,
+            raw: None,
+            portable_path: portable_path(&path),
+            path,
+        })
+    }
+
+    /// Returns the index of the first line holding anything other than whitespace and
+    /// comments.
+    pub fn first_code_line(&self) -> Option<usize> {
+        let mut in_block_comment = false;
+        self.lines.iter().position(|line| {
+            let mut rest = line.as_str();
+            loop {
+                if in_block_comment {
+                    match rest.find("*/") {
+                        Some(end) => {
+                            rest = &rest[(end + 2)..];
+                            in_block_comment = false;
+                        }
+                        None => return false,
+                    }
+                }
+                rest = rest.trim_start();
+                if rest.is_empty() || rest.starts_with("//") {
+                    return false;
+                } else if rest.starts_with("/*") {
+                    rest = &rest[2..];
+                    in_block_comment = true;
+                } else {
+                    return true;
+                }
+            }
+        })
+    }
+
+    /// Returns the first line whose indentation mixes tabs and spaces, or indents with a
+    /// different character than the lines before it. Blank lines are ignored.
+    pub fn mixed_indentation_line(&self) -> Option<usize> {
+        let mut indent_char = None;
+        self.lines.iter().position(|line| {
+            if line.trim().is_empty() {
+                return false;
+            }
+            let indent = &line[..(line.len() - line.trim_start().len())];
+            let tabs = indent.contains('\t');
+            let spaces = indent.contains(' ');
+            if tabs && spaces {
+                return true;
+            }
+            match (tabs || spaces, indent_char) {
+                (false, _) => false,
+                (true, None) => {
+                    indent_char = Some(tabs);
+                    false
+                }
+                (true, Some(indent_tabs)) => indent_tabs != tabs,
+            }
         })
     }
 
-    pub fn expired(&self) -> Result<bool> {
-        Ok(self.mtime < File::open(&self.path)?.metadata()?.modified()?)
+    /// Returns the lines of preprocessor directives which are rendered untouched rather than
+    /// evaluated, with their keywords, in line order.
+    pub fn passthrough_directives(&self) -> Vec<(usize, String)> {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| keyword(line).map(|(keyword, _)| (i, keyword)))
+            .filter(|(_, keyword)| PASSTHROUGH_KEYWORDS.contains(keyword))
+            .map(|(i, keyword)| (i, String::from(keyword)))
+            .collect()
+    }
+
+    /// Returns the number of lines in the comment block at the very start of the file, which
+    /// ends at the first line that isn't entirely comment. A block comment that ends partway
+    /// through a line can't be removed by whole lines, so such a block counts as no lines.
+    pub fn leading_comment_lines(&self) -> usize {
+        let mut in_block_comment = false;
+        for (i, line) in self.lines.iter().enumerate() {
+            let line = line.trim();
+            let rest = if in_block_comment {
+                line
+            } else if line.starts_with("//") {
+                continue;
+            } else if let Some(rest) = line.strip_prefix("/*") {
+                in_block_comment = true;
+                rest
+            } else {
+                return i;
+            };
+            if let Some(end) = rest.find("*/") {
+                if end + 2 != rest.len() {
+                    return 0;
+                }
+                in_block_comment = false;
+            }
+        }
+        if in_block_comment {
+            0
+        } else {
+            self.lines.len()
+        }
+    }
+
+    pub fn expired(&self, options: &Options) -> Result<bool> {
+        match self.mtime {
+            Some(mtime) => {
+                Ok(modified(options, &self.path)?.is_some_and(|modified| mtime < modified))
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Returns when the file at the given path was modified, according to the resolver.
+fn modified(options: &Options, path: &str) -> Result<Option<SystemTime>> {
+    options
+        .resolver
+        .0
+        .modified(Path::new(path))
+        .map_err(|error| match error.kind() {
+            io::ErrorKind::Unsupported => Error::MtimeUnsupported {
+                path: String::from(path),
+            },
+            _ => Error::Io(error),
+        })
+}
+
+#[cfg(windows)]
+fn portable_path(path: &str) -> String {
+    path.replace('\\', "/")
+}
+
+#[cfg(not(windows))]
+fn portable_path(path: &str) -> String {
+    String::from(path)
+}
+
+/// Joins directive lines ending in a backslash with the lines they continue onto, which are
+/// left blank. Returns, for each joined line, the offset within it at which each of its
+/// physical lines begins, with the offset in the source at which that line begins.
+fn join_continuations(
+    lines: &mut [String],
+    line_starts: &[usize],
+) -> HashMap<usize, Vec<(usize, usize)>> {
+    let mut continuations = HashMap::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let mut last = i;
+        if lines[i].trim_start().starts_with('#') {
+            while lines[last].ends_with('\\') && last + 1 < lines.len() {
+                last += 1;
+            }
+        }
+        if last > i {
+            let mut joined = String::new();
+            let mut segments = Vec::new();
+            for j in i..=last {
+                let line = std::mem::take(&mut lines[j]);
+                segments.push((joined.len(), line_starts[j]));
+                joined.push_str(if j < last { &line[..(line.len() - 1)] } else { &line });
+            }
+            lines[i] = joined;
+            continuations.insert(i, segments);
+        }
+        i = last + 1;
+    }
+    continuations
+}
+
+/// Returns the paths a file is looked for at, in order: the path itself, then the path in
+/// each search directory.
+fn candidates<'a>(path: &'a str, search_dirs: &'a [String]) -> impl Iterator<Item = PathBuf> + 'a {
+    iter::once(PathBuf::from(path))
+        .chain(search_dirs.iter().map(move |include_dir| Path::new(include_dir).join(path)))
+}
+
+/// Applies the configured normalizations to an include path as written in a directive.
+pub fn normalize_include(mut path: String, options: &Options) -> String {
+    for suffix in &options.include_suffixes {
+        if let Some(i) = path.find(suffix.as_str()) {
+            path.truncate(i);
+        }
+    }
+    if options.normalize_backslashes && path.contains('\\') {
+        path = path.replace('\\', "/");
+    }
+    // `.` segments don't change which file is meant, but would give it another key.
+    if path.split('/').any(|segment| segment == ".") {
+        path = path
+            .split('/')
+            .filter(|segment| *segment != ".")
+            .collect::<Vec<_>>()
+            .join("/");
+    }
+    match options.include_remaps.get(&path) {
+        Some(remapped) => remapped.clone(),
+        None => path,
+    }
+}
+
+/// A GLSL profile, as named in a version pragma.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Profile {
+    Core,
+    Compatibility,
+    Es,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Profile::Core => "core",
+            Profile::Compatibility => "compatibility",
+            Profile::Es => "es",
+        })
     }
 }
 
 #[derive(Debug)]
 enum Directive {
-    Version(usize),
-    Include(String),
+    Version(usize, Option<Profile>),
+    /// An include path, its byte range within the line, and whether the include is optional.
+    Include(String, Range<usize>, bool),
+    /// An include whose argument isn't a single delimited path.
+    MalformedInclude,
+    /// A line number and source string number.
+    Line(usize, Option<usize>),
+    /// An extension name and its behavior.
+    Extension(String, String),
+    Conditional(Conditional),
+}
+
+/// Returns the keyword of a directive line and the offset its arguments start at.
+fn keyword(line: &str) -> Option<(&str, usize)> {
+    match line.char_indices().find(|&(_, c)| !c.is_whitespace()) {
+        Some((i, '#')) => {
+            let keyword_len = line[(i + 1)..]
+                .find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or(line.len() - i - 1);
+            let arg_start = i + 1 + keyword_len;
+            Some((&line[(i + 1)..arg_start], arg_start))
+        }
+        _ => None,
+    }
 }
 
-fn directive(line: &str) -> Option<Directive> {
-    if let Some((i, '#')) = line.chars()
-        .enumerate()
-        .skip_while(|&(_, c)| c.is_whitespace())
-        .next()
-    {
-        match line.get((i + 1)..(i + 8)) {
-            Some("include") => match line.get((i + 9)..)
+fn directive(line: &str, options: &Options) -> Option<Directive> {
+    if let Some((keyword, arg_start)) = keyword(line) {
+        let rest = || String::from(line[arg_start..].trim());
+        let include = |optional| {
+            line.get(arg_start..)
                 .and_then(|s| INCLUDE_RE.captures(s))
-                .and_then(|c| c.get(2))
-            {
-                Some(path) => Some(Directive::Include(String::from(path.as_str()))),
-                None => None,
-            },
-            Some("version") => match line.get((i + 9)..)
+                .and_then(|c| c.get(1).or_else(|| c.get(2)))
+                .and_then(|path| {
+                    let trimmed = path.as_str().trim();
+                    let padded = trimmed.len() != path.as_str().len();
+                    if trimmed.is_empty() || (padded && options.reject_padded_include_paths) {
+                        return None;
+                    }
+                    let start = arg_start + path.start() + path.as_str().find(trimmed).unwrap();
+                    let span = start..(start + trimmed.len());
+                    Some(Directive::Include(String::from(trimmed), span, optional))
+                })
+                .or(Some(Directive::MalformedInclude))
+        };
+        match keyword {
+            "include" => include(false),
+            "include_optional" => include(true),
+            "import" if options.import_alias => include(false),
+            "version" if options.version_handling => line.get(arg_start..)
                 .and_then(|s| VERSION_RE.captures(s))
-                .and_then(|c| c.get(1))
-            {
-                Some(version) => Some(Directive::Version(
-                    version.as_str().parse::<usize>().unwrap(),
-                )),
-                None => None,
-            },
+                .map(|c| {
+                    let profile = c.get(2).map(|profile| match profile.as_str() {
+                        "core" => Profile::Core,
+                        "compatibility" => Profile::Compatibility,
+                        _ => Profile::Es,
+                    });
+                    Directive::Version(c[1].parse::<usize>().unwrap(), profile)
+                }),
+            "line" => LINE_RE.captures(&line[arg_start..]).and_then(|c| {
+                let number = c[1].parse().ok()?;
+                let source_string = match c.get(2) {
+                    Some(source_string) => Some(source_string.as_str().parse().ok()?),
+                    None => None,
+                };
+                Some(Directive::Line(number, source_string))
+            }),
+            "extension" => line.get(arg_start..)
+                .and_then(|s| EXTENSION_RE.captures(s))
+                .map(|c| Directive::Extension(String::from(&c[1]), String::from(&c[2]))),
+            "if" => Some(Directive::Conditional(Conditional::If(rest()))),
+            "ifdef" => Some(Directive::Conditional(Conditional::Ifdef(rest()))),
+            "ifndef" => Some(Directive::Conditional(Conditional::Ifndef(rest()))),
+            "elif" => Some(Directive::Conditional(Conditional::Elif(rest()))),
+            "else" => Some(Directive::Conditional(Conditional::Else)),
+            "endif" => Some(Directive::Conditional(Conditional::Endif)),
             _ => None,
         }
     } else {
@@ -117,11 +559,165 @@ mod test {
         let result = AnnotatedGLSL::load(
             "src/test_glsl/simple.vert",
             &[String::from("src/test_glsl")],
+            &Options::default(),
         ).expect("annotated glsl");
         assert_eq!(result.version_pragma, Some((0, 150)));
+        assert_eq!(result.profile, None);
         assert_eq!(result.includes, hashmap!{1 => String::from("common.vert")});
 
-        let expiry = result.expired().expect("expiry");
-        assert_eq!(expiry, false);
+        let expiry = result.expired(&Options::default()).expect("expiry");
+        assert!(!expiry);
+    }
+
+    #[test]
+    fn directive_offsets() {
+        let result = AnnotatedGLSL::load(
+            "src/test_glsl/simple.vert",
+            &[String::from("src/test_glsl")],
+            &Options::default(),
+        ).expect("annotated glsl");
+        assert_eq!(result.directive_offsets, hashmap!{0 => 0, 1 => 13});
+        assert_eq!(result.include_spans, hashmap!{1 => 23..34});
+
+        let src = std::fs::read_to_string("src/test_glsl/simple.vert").expect("source");
+        assert_eq!(&src[result.include_spans[&1].clone()], "common.vert");
+    }
+
+    #[test]
+    fn leading_comment_lines() {
+        let count = |src: &str| {
+            AnnotatedGLSL::parse(src, String::from("test.vert"), None, &Options::default())
+                .expect("annotated glsl")
+                .leading_comment_lines()
+        };
+        assert_eq!(count("// a\n// b\ncode"), 2);
+        assert_eq!(count("/* a\n b */\n// c\n\n// d"), 3);
+        assert_eq!(count("/* a */ code"), 0);
+        assert_eq!(count("/* a\nb */ code"), 0);
+        assert_eq!(count("/* a"), 0);
+        assert_eq!(count("code\n// a"), 0);
+    }
+
+    #[test]
+    fn version_profiles() {
+        let version = |src: &str| {
+            let src =
+                AnnotatedGLSL::parse(src, String::from("test.vert"), None, &Options::default())
+                    .expect("annotated glsl");
+            (src.version_pragma.map(|(_, version)| version), src.profile)
+        };
+        assert_eq!(version("#version 300 es"), (Some(300), Some(Profile::Es)));
+        assert_eq!(version("#version 330 core "), (Some(330), Some(Profile::Core)));
+        assert_eq!(
+            version("#version 150 compatibility"),
+            (Some(150), Some(Profile::Compatibility))
+        );
+        assert_eq!(version("#version 100"), (Some(100), None));
+        assert_eq!(version("#version 330 desktop"), (None, None));
+    }
+
+    #[test]
+    fn duplicate_versions() {
+        let src = "#version 150\nfloat a;\n#version 330\n";
+        let error = AnnotatedGLSL::parse(src, String::from("test.vert"), None, &Options::default())
+            .expect_err("duplicate version");
+        assert_eq!(error.to_string(), "test.vert has more than one version pragma, on lines 1, 3");
+        match error {
+            Error::DuplicateVersion { path, lines } => {
+                assert_eq!(path, "test.vert");
+                assert_eq!(lines, vec![0, 2]);
+            }
+            other => panic!("expected duplicate version, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn continued_directives() {
+        let src = "#include \\\n  \"a.glsl\"\nfloat a; // \\\nfloat b;\n\
+                   #extension GL_foo \\\n  : \\\n  enable\n";
+        let result = AnnotatedGLSL::parse(src, String::from("test.vert"), None, &Options::default())
+            .expect("annotated glsl");
+        assert_eq!(
+            result.lines,
+            vec![
+                "#include   \"a.glsl\"",
+                "",
+                "float a; // \\",
+                "float b;",
+                "#extension GL_foo   :   enable",
+                "",
+                "",
+            ]
+        );
+        assert_eq!(result.includes, hashmap!{0 => String::from("a.glsl")});
+        assert_eq!(&src[result.include_spans[&0].clone()], "a.glsl");
+        assert_eq!(
+            result.extensions,
+            hashmap!{4 => (String::from("GL_foo"), String::from("enable"))}
+        );
+        assert_eq!(result.directive_offsets[&4], src.find("#extension").unwrap());
+    }
+
+    #[test]
+    fn include_parse() {
+        let parse = |src: &str| {
+            AnnotatedGLSL::parse(src, String::from("test.vert"), None, &Options::default())
+        };
+        let includes = |src: &str| parse(src).expect("annotated glsl").includes;
+        assert_eq!(includes("#include \"a\""), hashmap!{0 => String::from("a")});
+        assert_eq!(includes("#include <a>"), hashmap!{0 => String::from("a")});
+        assert_eq!(includes("#include \"a\" // why"), hashmap!{0 => String::from("a")});
+        assert_eq!(includes("#include \"a\" /* why */"), hashmap!{0 => String::from("a")});
+        assert_eq!(
+            includes("#include<common.glsl>"),
+            hashmap!{0 => String::from("common.glsl")}
+        );
+        assert_eq!(includes("#include\"a\""), hashmap!{0 => String::from("a")});
+        assert_eq!(includes("#include \"a\" "), hashmap!{0 => String::from("a")});
+        assert_eq!(includes("#include <a>\t \r"), hashmap!{0 => String::from("a")});
+        assert_eq!(
+            includes("#include \"a.glsl\"\n#include \"a.glsl\"  "),
+            hashmap!{0 => String::from("a.glsl"), 1 => String::from("a.glsl")}
+        );
+        assert_eq!(includes("#include\t<a>"), hashmap!{0 => String::from("a")});
+        let spans = parse("  #include<a>").expect("annotated glsl").include_spans;
+        assert_eq!(spans, hashmap!{0 => 11..12});
+        assert_eq!(includes("#include \" a.glsl\t\""), hashmap!{0 => String::from("a.glsl")});
+        assert_eq!(includes("#include \"./a.glsl\""), hashmap!{0 => String::from("a.glsl")});
+        assert_eq!(includes("#include \"a/./b/../c\""), hashmap!{0 => String::from("a/b/../c")});
+        assert_eq!(includes("#include \"/./a\""), hashmap!{0 => String::from("/a")});
+        let optional = parse("#include \"a\"\n#include_optional <b>").expect("annotated glsl");
+        assert_eq!(optional.includes, hashmap!{0 => String::from("a"), 1 => String::from("b")});
+        assert_eq!(optional.optional_includes, [1].iter().cloned().collect());
+        let spans = parse("#include < a >").expect("annotated glsl").include_spans;
+        assert_eq!(spans, hashmap!{0 => 11..12});
+        let options = Options {
+            reject_padded_include_paths: true,
+            ..Options::default()
+        };
+        let parse_strict = |src: &str| {
+            AnnotatedGLSL::parse(src, String::from("test.vert"), None, &options)
+        };
+        assert!(parse_strict("#include \"a\"").is_ok());
+        match parse_strict("#include \" a \"") {
+            Err(Error::MalformedInclude { line: 0, .. }) => (),
+            other => panic!("expected malformed include, got {:?}", other),
+        }
+        let malformed_includes = [
+            "#include \"a\" extra",
+            "#include \"a\"\"b\"",
+            "#include",
+            "#include a",
+            "#include \"  \"",
+        ];
+        for malformed in &malformed_includes {
+            match parse(&format!("\n{}", malformed)) {
+                Err(Error::MalformedInclude { path, line }) => {
+                    assert_eq!(path, "test.vert");
+                    assert_eq!(line, 1);
+                }
+                other => panic!("expected malformed include for {}, got {:?}", malformed, other),
+            }
+        }
     }
 }
@@ -0,0 +1,143 @@
+//! Access to the sources a tree is traced from.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+#[cfg(feature = "include_dir")]
+use include_dir::Dir;
+#[cfg(feature = "include_dir")]
+use std::path::{Component, PathBuf};
+
+/// Reads the sources of a tree. Trees read from the filesystem by default; set another
+/// resolver with `GLSLTreeBuilder::resolver` to trace sources kept elsewhere.
+///
+/// Paths are the candidates the tree tries for each file, i.e. the root path and each
+/// include path joined onto each include directory, in order. A resolver which has nothing
+/// at a path should fail with an error of kind `io::ErrorKind::NotFound`, so the next
+/// candidate is tried.
+pub trait Resolver: Send + Sync {
+    /// Reads the source at the given path.
+    fn read(&self, path: &Path) -> io::Result<String>;
+
+    /// Returns when the source at the given path was last modified, or `None` if its
+    /// sources never change, in which case it never expires. Failing with an error of kind
+    /// `io::ErrorKind::Unsupported` fails tracing with `Error::MtimeUnsupported`.
+    fn modified(&self, path: &Path) -> io::Result<Option<SystemTime>>;
+
+    /// Returns whether there is a source at the given path. By default, the source is read
+    /// to find out.
+    fn exists(&self, path: &Path) -> bool {
+        self.read(path).is_ok()
+    }
+
+    /// Returns the size in bytes of the source at the given path, if it can be told without
+    /// reading it, so oversized files are rejected before they are read. Sizes are unknown
+    /// by default.
+    fn size(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+/// Resolves sources from the filesystem.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsResolver;
+
+impl Resolver for FsResolver {
+    fn read(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<Option<SystemTime>> {
+        Ok(Some(fs::metadata(path)?.modified()?))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn size(&self, path: &Path) -> io::Result<Option<u64>> {
+        Ok(Some(fs::metadata(path)?.len()))
+    }
+}
+
+/// Resolves sources from a directory embedded in the binary with the `include_dir` crate,
+/// with paths relative to the embedded directory. Embedded sources never expire.
+///
+/// ```
+/// static SHADERS: Dir = include_dir!("$CARGO_MANIFEST_DIR/shaders");
+///
+/// let src_tree = GLSLTree::builder("frag.glsl")
+///     .include_dirs(&["include"])
+///     .resolver(EmbeddedResolver::new(&SHADERS))
+///     .build()?;
+/// ```
+#[cfg(feature = "include_dir")]
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedResolver {
+    dir: &'static Dir<'static>,
+}
+
+#[cfg(feature = "include_dir")]
+impl EmbeddedResolver {
+    pub fn new(dir: &'static Dir<'static>) -> Self {
+        EmbeddedResolver { dir }
+    }
+}
+
+#[cfg(feature = "include_dir")]
+impl Resolver for EmbeddedResolver {
+    fn read(&self, path: &Path) -> io::Result<String> {
+        // Embedded paths are stored without `.` components.
+        let path: PathBuf = path
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect();
+        let file = self.dir.get_file(&path).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no such embedded file")
+        })?;
+        file.contents_utf8().map(String::from).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "embedded file is not valid UTF-8")
+        })
+    }
+
+    fn modified(&self, _: &Path) -> io::Result<Option<SystemTime>> {
+        Ok(None)
+    }
+}
+
+#[cfg(all(test, feature = "include_dir"))]
+mod test {
+    use super::*;
+    use include_dir::include_dir;
+    use {Error, GLSLTree};
+
+    static TEST_GLSL: Dir = include_dir!("$CARGO_MANIFEST_DIR/src/test_glsl");
+
+    #[test]
+    fn embedded_tree() {
+        let tree = GLSLTree::builder("simple.vert")
+            .resolver(EmbeddedResolver::new(&TEST_GLSL))
+            .build()
+            .expect("embedded tree");
+        let from_disk =
+            GLSLTree::new("src/test_glsl/simple.vert", &["src/test_glsl"]).expect("my tree");
+        assert_eq!(tree.render(), from_disk.render());
+        assert!(!tree.expired().expect("expiry"));
+
+        let tree = GLSLTree::builder("./simple.vert")
+            .resolver(EmbeddedResolver::new(&TEST_GLSL))
+            .build()
+            .expect("embedded tree with dotted root");
+        assert_eq!(tree.render(), from_disk.render());
+
+        match GLSLTree::builder("missing.vert")
+            .resolver(EmbeddedResolver::new(&TEST_GLSL))
+            .build()
+        {
+            Err(Error::FailedToOpen { path, .. }) => assert_eq!(path, "missing.vert"),
+            other => panic!("expected failure to open, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,3 @@
+float someotherfunc() {
+    return 2.0;
+}
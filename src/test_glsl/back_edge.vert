@@ -0,0 +1,3 @@
+#version 150
+#include "back_edge_config.vert"
+void main() {}
@@ -0,0 +1,2 @@
+#include "back_edge.vert"
+const float CONFIG = 1.0;
@@ -0,0 +1,7 @@
+// A commented header.
+/* Which continues
+   over lines. */ /* twice */
+
+#version 330
+void main() {
+}
@@ -0,0 +1,11 @@
+#version 330
+#if defined(FANCY) && QUALITY >= 2
+#include "fancy.vert"
+#elif !defined(FANCY)
+#include "nested.vert"
+#else
+#include "missing_plain.vert"
+#endif
+
+void main() {
+}
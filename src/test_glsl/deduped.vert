@@ -0,0 +1,3 @@
+#version 150
+#include "nested.vert"
+#include "secondary.vert"
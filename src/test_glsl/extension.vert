@@ -0,0 +1,6 @@
+#version 330
+#include "extension_a.vert"
+#include "extension_b.vert"
+
+void main() {
+}
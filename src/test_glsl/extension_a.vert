@@ -0,0 +1,4 @@
+float a() {
+    return 1.0;
+}
+#include "extension_header.vert"
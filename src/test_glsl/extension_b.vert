@@ -0,0 +1,2 @@
+#include "extension_header.vert"
+#extension GL_ARB_shading_language_420pack : require
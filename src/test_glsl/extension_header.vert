@@ -0,0 +1,4 @@
+#extension GL_OES_standard_derivatives : enable
+float header() {
+    return 2.0;
+}
@@ -0,0 +1,3 @@
+float fancy() {
+    return 3.0;
+}
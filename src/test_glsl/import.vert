@@ -0,0 +1,3 @@
+#version 150
+#import "nested.vert"
+#include "nested.vert"
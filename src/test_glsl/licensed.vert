@@ -0,0 +1,5 @@
+// Copyright (c) Example Authors.
+// Licensed under the MIT license.
+#version 150
+#include "licensed_a.vert"
+#include "licensed_b.vert"
@@ -0,0 +1,4 @@
+// Copyright (c) Example Authors.
+// Licensed under the MIT license.
+
+float a() { return 1.0; }
@@ -0,0 +1,5 @@
+/*
+ * Copyright (c) Example Authors.
+ * Licensed under the MIT license.
+ */
+float b() { return 2.0; } // not a header
@@ -0,0 +1,5 @@
+#include "util.wgsl"
+@fragment
+fn main() -> @location(0) vec4<f32> {
+    return color();
+}
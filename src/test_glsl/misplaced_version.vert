@@ -0,0 +1,4 @@
+float x() {
+    return 0.0;
+}
+#version 330
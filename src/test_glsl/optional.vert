@@ -0,0 +1,6 @@
+#version 150
+#include_optional "platform.vert"
+#include_optional "nested.vert"
+
+void main() {
+}
@@ -0,0 +1,3 @@
+fn color() -> vec4<f32> {
+    return vec4<f32>(1.0);
+}